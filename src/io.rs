@@ -1,35 +1,131 @@
-use std::io::Write;
+use std::io::{Read, Write};
 
-pub struct Io<W> {
+pub struct Io<W, R> {
     pub writer: W,
+    pub reader: R,
+    captures: Vec<Vec<u8>>, // while not empty, output goes to the innermost capture instead of the writer
 }
 
-impl<W> Io<W>
+impl<W> Io<W, std::io::Empty>
 where
     W: Write,
 {
     pub fn new(writer: W) -> Self {
-        Self { writer }
+        Self {
+            writer,
+            reader: std::io::empty(),
+            captures: Vec::new(),
+        }
+    }
+}
+
+impl<W, R> Io<W, R>
+where
+    W: Write,
+    R: Read,
+{
+    pub fn with_reader(writer: W, reader: R) -> Self {
+        Self {
+            writer,
+            reader,
+            captures: Vec::new(),
+        }
+    }
+
+    // returns None at the end of input
+    pub fn read_byte(&mut self) -> std::io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.reader.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    // reads up to and including the next newline, empty only at the end of input
+    pub fn read_line(&mut self) -> std::io::Result<Vec<u8>> {
+        let mut line = Vec::new();
+        while let Some(byte) = self.read_byte()? {
+            line.push(byte);
+            if byte == b'\n' {
+                break;
+            }
+        }
+        Ok(line)
+    }
+}
+
+impl<W, R> Io<W, R> {
+    // copies every byte the program reads to `log`, which can later be read back to replay the run
+    pub fn recording<L: Write>(self, log: L) -> Io<W, Recorder<R, L>> {
+        Io {
+            writer: self.writer,
+            reader: Recorder {
+                reader: self.reader,
+                log,
+            },
+            captures: self.captures,
+        }
+    }
+
+    pub fn start_capture(&mut self) {
+        self.captures.push(Vec::new());
+    }
+
+    // returns None when no capture was started
+    pub fn end_capture(&mut self) -> Option<Vec<u8>> {
+        self.captures.pop()
     }
 }
 
-impl<W> Write for Io<W>
+impl<W, R> Write for Io<W, R>
 where
     W: Write,
 {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.writer.write(buf)
+        match self.captures.last_mut() {
+            Some(capture) => capture.write(buf),
+            None => self.writer.write(buf),
+        }
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
-        self.writer.flush()
+        match self.captures.last_mut() {
+            Some(_) => Ok(()),
+            None => self.writer.flush(),
+        }
     }
 }
 
-impl Default for Io<std::io::Stdout> {
+impl Default for Io<std::io::Stdout, std::io::Stdin> {
     fn default() -> Self {
         Self {
             writer: std::io::stdout(),
+            reader: std::io::stdin(),
+            captures: Vec::new(),
         }
     }
 }
+
+// a reader logging what is read through it, so input of interactive runs can be replayed
+pub struct Recorder<R, L> {
+    reader: R,
+    log: L,
+}
+
+impl<R, L> Recorder<R, L> {
+    pub fn into_log(self) -> L {
+        self.log
+    }
+}
+
+impl<R, L> Read for Recorder<R, L>
+where
+    R: Read,
+    L: Write,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.log.write_all(&buf[..len])?;
+        Ok(len)
+    }
+}
@@ -1,14 +1,33 @@
 use stack_lang::io::Io;
 
 fn main() {
-    let program_source = std::fs::read_to_string(
-        std::env::args()
-            .nth(1)
-            .unwrap_or_else(|| String::from("examples/hello_world.rsl")),
-    )
-    .unwrap();
-    let program = stack_lang::program::Program::parse(&program_source);
+    if std::env::args().nth(1).as_deref() == Some("--repl") {
+        stack_lang::program::Repl::new()
+            .run(&mut Io::default())
+            .unwrap();
+        return;
+    }
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| String::from("examples/hello_world.rsl"));
+    let program = match stack_lang::program::Program::parse_file(path) {
+        Ok(program) => program,
+        Err(error) => {
+            eprintln!("error {error}");
+            std::process::exit(1);
+        }
+    };
+    if let Err(error) = program.validate() {
+        eprintln!("error: {error}");
+        std::process::exit(1);
+    }
+    for function in program.unreachable_functions(&["main"]) {
+        eprintln!("warning: function `{function}` is never called");
+    }
 
-    program.interpret(&mut Io::default());
+    if let Err(error) = program.interpret(&mut Io::default()) {
+        eprintln!("error: {error}");
+        std::process::exit(1);
+    }
     println!()
 }
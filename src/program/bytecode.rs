@@ -0,0 +1,446 @@
+use super::{
+    machine::{match_arm, pop},
+    Config, Machine, Program, RuntimeError, Stack, RECURSION_LIMIT,
+};
+use crate::io::Io;
+use crate::token::*;
+use std::io::{Read, Write};
+
+// the program lowered into a single list of instructions, where blocks are replaced by jumps
+#[derive(Debug)]
+pub struct CompiledProgram<'p> {
+    program: &'p Program,
+    instructions: Vec<Instruction<'p>>,
+    functions: Vec<Option<Entry>>, // by the index calls refer to, None for the ones that are not defined
+}
+
+#[derive(Debug)]
+enum Instruction<'p> {
+    Execute(&'p Token), // a token without blocks, run the same way as by the tree-walking machine
+    Jump(usize),
+    JumpIfZero(usize, &'p Token),   // pops the condition of the block
+    Match(&'p Token, Box<[usize]>), // the start of every arm, followed by the start of the else block
+    Call(usize, Box<[Loop<'p>]>), // the loops around the call, which a break or continue in it can leave
+    Return,
+    Leave(Option<&'p str>, bool), // a break, or a continue when set, outside of the loops of the function
+    Load(usize),                  // pushes the variable in the slot
+    Store(usize, &'p Token),      // pops into the slot
+    Reset(usize),                 // sets the slot to zero
+    // binds the next value of the counted loop, jumping past it once the next value reaches the end
+    ForNext {
+        variable: Option<usize>,
+        next: usize,
+        end: usize,
+        exit: usize,
+    },
+}
+
+// where a function starts, and how many slots its parameters and variables take
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    start: usize,
+    parameters: usize,
+    slots: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Loop<'p> {
+    label: Option<&'p str>, // never set for counted loops, which only unlabeled breaks leave
+    continue_to: usize,
+    break_to: usize,
+}
+
+impl Loop<'_> {
+    // any loop when no label is given, otherwise only the loop with that label
+    fn matches(&self, label: Option<&str>) -> bool {
+        label.is_none() || label == self.label
+    }
+}
+
+// the instructions lowered so far, with the variables and loops of the function being lowered
+struct Compiler<'p> {
+    instructions: Vec<Instruction<'p>>,
+    variables: Vec<(&'p str, usize)>, // the visible parameters and bindings with their slots, innermost last
+    slots: usize,
+    loops: Vec<Loop<'p>>,
+}
+
+impl Program {
+    // lowers every function into instructions run with an instruction pointer, which is faster than walking the tokens
+    pub fn compile(&self) -> CompiledProgram<'_> {
+        let mut compiler = Compiler {
+            instructions: Vec::new(),
+            variables: Vec::new(),
+            slots: 0,
+            loops: Vec::new(),
+        };
+        let mut functions = vec![None; self.functions.len()];
+        for (index, _, function) in self.functions.iter() {
+            compiler.variables = function
+                .parameters
+                .iter()
+                .map(String::as_str)
+                .zip(0..)
+                .collect();
+            compiler.slots = function.parameters.len();
+            let start = compiler.instructions.len();
+            compiler.compile_segment(&function.body);
+            compiler.instructions.push(Instruction::Return);
+            functions[index] = Some(Entry {
+                start,
+                parameters: function.parameters.len(),
+                slots: compiler.slots,
+            });
+        }
+        CompiledProgram {
+            program: self,
+            instructions: compiler.instructions,
+            functions,
+        }
+    }
+}
+
+impl<'p> Compiler<'p> {
+    fn compile_segment(&mut self, segment: &'p [Token]) {
+        for token in segment {
+            match token {
+                Token::IfBlock(true_block, false_block)
+                | Token::Conditional(true_block, false_block) => {
+                    let branch = self.emit(Instruction::JumpIfZero(0, token));
+                    self.compile_segment(true_block);
+                    let end = self.emit(Instruction::Jump(0));
+                    self.patch(branch);
+                    self.compile_segment(false_block);
+                    self.patch(end);
+                }
+                Token::MatchBlock(arms, default, _) => {
+                    let index = self.emit(Instruction::Match(token, Box::new([])));
+                    let mut starts = Vec::new();
+                    let mut ends = Vec::new();
+                    for (_, arm) in arms {
+                        starts.push(self.instructions.len());
+                        self.compile_segment(arm);
+                        ends.push(self.emit(Instruction::Jump(0)));
+                    }
+                    starts.push(self.instructions.len());
+                    self.compile_segment(default);
+                    for end in ends {
+                        self.patch(end);
+                    }
+                    self.instructions[index] = Instruction::Match(token, starts.into());
+                }
+                Token::LoopBlock(body, label) => {
+                    self.open_loop(label.as_deref());
+                    self.compile_segment(body);
+                    self.close_loop();
+                }
+                Token::WhileBlock(condition, body, label) => {
+                    self.open_loop(label.as_deref());
+                    self.compile_segment(condition);
+                    let exit = self.emit(Instruction::JumpIfZero(0, token));
+                    self.compile_segment(body);
+                    self.close_loop();
+                    self.patch(exit);
+                }
+                Token::ForBlock(variable, start, end, body) => {
+                    self.compile_segment(start);
+                    self.compile_segment(end);
+                    let [end, next] = [self.slot(), self.slot()];
+                    self.emit(Instruction::Store(end, token));
+                    self.emit(Instruction::Store(next, token));
+                    let slot = self.slot();
+                    self.variables.push((variable, slot));
+                    self.counted_loop(Some(slot), next, end, body);
+                    self.variables.pop();
+                }
+                Token::RepeatBlock(body) => {
+                    let [end, next] = [self.slot(), self.slot()];
+                    self.emit(Instruction::Store(end, token));
+                    self.emit(Instruction::Reset(next));
+                    self.counted_loop(None, next, end, body);
+                }
+                Token::LetBlock(segment, let_bindings) => {
+                    let visible = self.variables.len();
+                    for let_binding in let_bindings {
+                        let slot = self.slot();
+                        self.emit(Instruction::Store(slot, token));
+                        self.variables.push((let_binding, slot));
+                    }
+                    self.compile_segment(segment);
+                    self.variables.truncate(visible);
+                }
+                Token::Let(let_binding) => {
+                    let slot = self.variable(let_binding);
+                    self.emit(Instruction::Load(slot));
+                }
+                Token::SetLet(let_binding) => {
+                    let slot = self.variable(let_binding);
+                    self.emit(Instruction::Store(slot, token));
+                }
+                Token::Break(label) | Token::Continue(label) => {
+                    let label = label.as_deref();
+                    let is_continue = matches!(token, Token::Continue(_));
+                    let instruction = match self.loops.iter().rev().find(|l| l.matches(label)) {
+                        Some(found) if is_continue => Instruction::Jump(found.continue_to),
+                        Some(found) => Instruction::Jump(found.break_to),
+                        None => Instruction::Leave(label, is_continue),
+                    };
+                    self.emit(instruction);
+                }
+                Token::Return => {
+                    self.emit(Instruction::Return);
+                }
+                Token::FunctionCall(index) => {
+                    self.emit(Instruction::Call(*index, self.loops.clone().into()));
+                }
+                token => {
+                    self.emit(Instruction::Execute(token));
+                }
+            }
+        }
+    }
+
+    fn emit(&mut self, instruction: Instruction<'p>) -> usize {
+        self.instructions.push(instruction);
+        self.instructions.len() - 1
+    }
+
+    // points the jump at the instruction emitted next
+    fn patch(&mut self, index: usize) {
+        let target = self.instructions.len();
+        match &mut self.instructions[index] {
+            Instruction::Jump(to) | Instruction::JumpIfZero(to, _) => *to = target,
+            Instruction::ForNext { exit, .. } => *exit = target,
+            instruction => unreachable!("{instruction:?} has no target"),
+        }
+    }
+
+    fn slot(&mut self) -> usize {
+        self.slots += 1;
+        self.slots - 1
+    }
+
+    // the parser only accepts names that are bound around their use
+    fn variable(&self, name: &str) -> usize {
+        self.variables
+            .iter()
+            .rev()
+            .find(|(variable, _)| *variable == name)
+            .map(|(_, slot)| *slot)
+            .expect("use of an unbound variable")
+    }
+
+    // the loop starts with a jump over the one its breaks jump to, whose target is patched when the loop is closed
+    fn open_loop(&mut self, label: Option<&'p str>) {
+        let start = self.instructions.len();
+        self.emit(Instruction::Jump(start + 2));
+        self.emit(Instruction::Jump(0));
+        self.loops.push(Loop {
+            label,
+            continue_to: start + 2,
+            break_to: start + 1,
+        });
+    }
+
+    fn close_loop(&mut self) {
+        let closed = self.loops.pop().unwrap();
+        self.emit(Instruction::Jump(closed.continue_to));
+        self.patch(closed.break_to);
+    }
+
+    fn counted_loop(
+        &mut self,
+        variable: Option<usize>,
+        next: usize,
+        end: usize,
+        body: &'p [Token],
+    ) {
+        self.open_loop(None);
+        let exit = self.emit(Instruction::ForNext {
+            variable,
+            next,
+            end,
+            exit: 0,
+        });
+        self.compile_segment(body);
+        self.close_loop();
+        self.patch(exit);
+    }
+}
+
+// a function that has been called and has not returned yet
+struct Frame<'c> {
+    return_to: usize,
+    base: usize, // index of its first slot
+    loops: &'c [Loop<'c>],
+}
+
+// the state of a run, the machine holds the stack and memory and runs the tokens without blocks
+struct Run<'c, 'p> {
+    compiled: &'c CompiledProgram<'p>,
+    machine: Machine<'p>,
+    slots: Vec<usize>, // the parameters and variables of every frame
+    frames: Vec<Frame<'c>>,
+}
+
+impl<'p> CompiledProgram<'p> {
+    pub fn interpret<W: Write, R: Read>(&self, io: &mut Io<W, R>) -> Result<(), RuntimeError> {
+        self.run_with_config(io, &mut Config::default()).map(|_| ())
+    }
+
+    // runs the program like `Program::run_with_config`, except that configs with a hook or stats and programs
+    // reaching a `yield` fail, as they need a host stepping the machine
+    pub fn run_with_config<W: Write, R: Read>(
+        &self,
+        io: &mut Io<W, R>,
+        config: &mut Config,
+    ) -> Result<Machine<'p>, RuntimeError> {
+        if config.hook.is_some() {
+            return Err(RuntimeError::NotCompilable("hook"));
+        }
+        if config.stats.is_some() {
+            return Err(RuntimeError::NotCompilable("stats"));
+        }
+        let entry_point = config.entry_point.unwrap_or("main");
+        let index = self
+            .program
+            .functions
+            .index(entry_point)
+            .filter(|&index| self.functions[index].is_some())
+            .ok_or_else(|| RuntimeError::MissingEntryPoint(entry_point.to_string()))?;
+        let memory = Machine::globals(self.program, config)?;
+        let mut run = Run {
+            compiled: self,
+            machine: Machine::idle(self.program, Stack::new(), memory, config),
+            slots: Vec::new(),
+            frames: Vec::new(),
+        };
+        let start = run.call(index, usize::MAX, &[], config)?;
+        run.run(start, io, config)?;
+        self.program.check_leaks(run.machine.memory(), config)?;
+        Ok(run.machine)
+    }
+}
+
+impl<'c> Run<'c, '_> {
+    // runs from the instruction until the entry point returns
+    fn run<W: Write, R: Read>(
+        &mut self,
+        mut ip: usize,
+        io: &mut Io<W, R>,
+        config: &mut Config,
+    ) -> Result<(), RuntimeError> {
+        let instructions = &self.compiled.instructions;
+        while let Some(instruction) = instructions.get(ip) {
+            ip += 1;
+            match instruction {
+                Instruction::Execute(Token::Yield) => {
+                    return Err(RuntimeError::NotCompilable("yield"))
+                }
+                Instruction::Execute(token) => self.machine.execute(token, io, config)?,
+                Instruction::Jump(to) => ip = *to,
+                Instruction::JumpIfZero(to, token) => {
+                    if pop(self.machine.stack_mut(), token)? == 0 {
+                        ip = *to;
+                    }
+                }
+                Instruction::Match(token, starts) => {
+                    let Token::MatchBlock(arms, _, table) = token else {
+                        unreachable!()
+                    };
+                    let value = pop(self.machine.stack_mut(), token)?;
+                    let arm = match_arm(arms, table.as_ref(), value);
+                    ip = starts[arm.unwrap_or(arms.len())];
+                }
+                Instruction::Call(index, loops) => ip = self.call(*index, ip, loops, config)?,
+                Instruction::Return => {
+                    let frame = self.frames.pop().unwrap();
+                    self.slots.truncate(frame.base);
+                    ip = frame.return_to;
+                }
+                // returns until a call is inside of a loop the break or continue can leave
+                Instruction::Leave(label, is_continue) => {
+                    ip = usize::MAX;
+                    while let Some(frame) = self.frames.pop() {
+                        self.slots.truncate(frame.base);
+                        if let Some(found) = frame.loops.iter().rev().find(|l| l.matches(*label)) {
+                            ip = match is_continue {
+                                true => found.continue_to,
+                                false => found.break_to,
+                            };
+                            break;
+                        }
+                    }
+                }
+                Instruction::Load(slot) => {
+                    let value = self.slots[self.base() + slot];
+                    self.machine.stack_mut().push(value);
+                }
+                Instruction::Store(slot, token) => {
+                    let base = self.base();
+                    self.slots[base + slot] = pop(self.machine.stack_mut(), token)?;
+                }
+                Instruction::Reset(slot) => {
+                    let base = self.base();
+                    self.slots[base + slot] = 0;
+                }
+                Instruction::ForNext {
+                    variable,
+                    next,
+                    end,
+                    exit,
+                } => {
+                    let base = self.base();
+                    let value = self.slots[base + next];
+                    if value < self.slots[base + end] {
+                        if let Some(variable) = variable {
+                            self.slots[base + variable] = value;
+                        }
+                        self.slots[base + next] = value.wrapping_add(1);
+                    } else {
+                        ip = *exit;
+                    }
+                }
+            }
+            self.machine.check_limits(config)?;
+        }
+        Ok(())
+    }
+
+    // index of the first slot of the innermost function
+    fn base(&self) -> usize {
+        self.frames.last().map_or(0, |frame| frame.base)
+    }
+
+    // binds the parameters in new slots, returning where the function starts
+    fn call(
+        &mut self,
+        index: usize,
+        return_to: usize,
+        loops: &'c [Loop<'c>],
+        config: &Config,
+    ) -> Result<usize, RuntimeError> {
+        let name = self.compiled.program.functions.name(index);
+        let entry = self.compiled.functions[index]
+            .ok_or_else(|| RuntimeError::UnknownFunction(name.to_string()))?;
+        let limit = config.recursion_limit.unwrap_or(RECURSION_LIMIT);
+        if self.frames.len() >= limit {
+            return Err(RuntimeError::RecursionLimit(limit));
+        }
+        let base = self.slots.len();
+        self.slots.resize(base + entry.slots, 0);
+        for parameter in (0..entry.parameters).rev() {
+            self.slots[base + parameter] = self
+                .machine
+                .stack_mut()
+                .pop()
+                .ok_or_else(|| RuntimeError::StackUnderflow(name.to_string()))?;
+        }
+        self.frames.push(Frame {
+            return_to,
+            base,
+            loops,
+        });
+        Ok(entry.start)
+    }
+}
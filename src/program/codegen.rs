@@ -0,0 +1,717 @@
+use super::{Functions, Program};
+use crate::token::*;
+
+// runtime shared by every generated program, mirroring the interpreter
+const PRELUDE: &str = r##"#![allow(dead_code, unused_mut, unused_variables, unreachable_code)]
+
+use memory::{Memory, WORD_SIZE};
+use std::io::{Read, Write};
+
+// a `break` or `continue` outside of the loops of a function is caught by the loop around its call,
+// true when it continues
+type Leave = Option<bool>;
+
+struct Machine {
+    stack: Vec<usize>,
+    frames: Vec<usize>,
+    memory: Memory,
+    output: std::io::Stdout,
+    input: std::io::Stdin,
+    captures: Vec<Vec<u8>>,
+    seed: u64,
+    byte_stack: (usize, usize, usize), // base, capacity and length
+}
+
+impl Machine {
+    fn pop(&mut self) -> usize {
+        if self.stack.len() <= self.frames.last().copied().unwrap_or(0) {
+            panic!("stack underflow");
+        }
+        self.stack.pop().unwrap()
+    }
+
+    fn push_frame(&mut self) {
+        let len = self.pop();
+        let base = self.frames.last().copied().unwrap_or(0);
+        if len > self.stack.len() - base {
+            panic!("stack underflow");
+        }
+        self.frames.push(self.stack.len() - len);
+    }
+
+    fn pick(&mut self) {
+        let index = self.pop();
+        let base = self.frames.last().copied().unwrap_or(0);
+        if index >= self.stack.len() - base {
+            panic!("stack underflow");
+        }
+        self.stack.push(self.stack[self.stack.len() - 1 - index]);
+    }
+
+    fn unary(&mut self, operation: impl Fn(usize) -> usize) {
+        let a = self.pop();
+        self.stack.push(operation(a));
+    }
+
+    fn binary(&mut self, operation: impl Fn(usize, usize) -> usize) {
+        let b = self.pop();
+        let a = self.pop();
+        self.stack.push(operation(a, b));
+    }
+
+    fn shift(&mut self, left: bool) {
+        let b = self.pop();
+        let a = self.pop();
+        if b >= usize::BITS as usize {
+            panic!("shift amount {b} exceeds the word width");
+        }
+        self.stack.push(if left { a << b } else { a >> b });
+    }
+
+    fn write(&mut self, text: &str) {
+        self.write_bytes(text.as_bytes());
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        match self.captures.last_mut() {
+            Some(capture) => capture.extend_from_slice(bytes),
+            None => {
+                self.output.write_all(bytes).unwrap();
+                self.output.flush().unwrap();
+            }
+        }
+    }
+
+    fn puts(&mut self) {
+        let address = self.pop();
+        let bytes = self
+            .memory
+            .get_terminated(address)
+            .expect("memory access out of bounds")
+            .to_vec();
+        self.write_bytes(&bytes);
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut byte = [0];
+        match self.input.read(&mut byte).unwrap() {
+            0 => None,
+            _ => Some(byte[0]),
+        }
+    }
+
+    fn getu(&mut self) {
+        let mut byte = self.read_byte();
+        while byte.is_some_and(|byte| byte.is_ascii_whitespace()) {
+            byte = self.read_byte();
+        }
+        let mut value = None;
+        while let Some(digit) = byte.filter(u8::is_ascii_digit) {
+            let digit = (digit - b'0') as usize;
+            value = Some(value.unwrap_or(0usize).wrapping_mul(10).wrapping_add(digit));
+            byte = self.read_byte();
+        }
+        self.stack.push(value.unwrap_or(usize::MAX));
+    }
+
+    fn load_byte(&mut self) {
+        let address = self.pop();
+        let value = *self.memory.get(address).expect("memory access out of bounds");
+        self.stack.push(value as usize);
+    }
+
+    fn store_byte(&mut self) {
+        let value = self.pop();
+        let address = self.pop();
+        self.memory.set(address, value as u8).expect("memory access out of bounds");
+    }
+
+    fn load_word(&mut self) {
+        let address = self.pop();
+        let value = self.memory.get_word(address).expect("memory access out of bounds");
+        self.stack.push(value);
+    }
+
+    fn store_word(&mut self) {
+        let value = self.pop();
+        let address = self.pop();
+        self.memory.set_word(address, value).expect("memory access out of bounds");
+    }
+
+    fn step_word(&mut self, increment: bool) {
+        let address = self.pop();
+        let value = self.memory.get_word(address).expect("memory access out of bounds");
+        let value = if increment { value.wrapping_add(1) } else { value.wrapping_sub(1) };
+        self.memory.set_word(address, value);
+        self.stack.push(value);
+    }
+
+    fn copy(&mut self) {
+        let len = self.pop();
+        let source = self.pop();
+        let destination = self.pop();
+        self.memory
+            .copy_within(destination, source, len)
+            .expect("memory access out of bounds");
+    }
+
+    fn fill(&mut self) {
+        let len = self.pop();
+        let value = self.pop();
+        let address = self.pop();
+        self.memory
+            .fill(address, value as u8, len)
+            .expect("memory access out of bounds");
+    }
+
+    fn crlf_to_lf(&mut self) {
+        let len = self.pop();
+        let address = self.pop();
+        let bytes = self
+            .memory
+            .get_range_mut(address, len)
+            .expect("memory access out of bounds");
+        let mut new_len = 0;
+        for i in 0..bytes.len() {
+            if bytes[i] == b'\r' && bytes.get(i + 1) == Some(&b'\n') {
+                continue;
+            }
+            bytes[new_len] = bytes[i];
+            new_len += 1;
+        }
+        self.stack.push(new_len);
+    }
+
+    fn histogram(&mut self) {
+        let destination = self.pop();
+        let len = self.pop();
+        let address = self.pop();
+        let mut counts = [0; 256];
+        for byte in self.memory.get_range(address, len).expect("memory access out of bounds") {
+            counts[*byte as usize] += 1;
+        }
+        for (i, count) in counts.into_iter().enumerate() {
+            self.memory
+                .set_word(destination + i * WORD_SIZE, count)
+                .expect("memory access out of bounds");
+        }
+    }
+
+    fn edit_distance(&mut self) {
+        let b_len = self.pop();
+        let b_address = self.pop();
+        let a_len = self.pop();
+        let a_address = self.pop();
+        let a = self.memory.get_range(a_address, a_len).expect("memory access out of bounds");
+        let b = self.memory.get_range(b_address, b_len).expect("memory access out of bounds");
+        let mut previous: Vec<usize> = (0..=b.len()).collect();
+        let mut current = vec![0; b.len() + 1];
+        for (i, a) in a.iter().enumerate() {
+            current[0] = i + 1;
+            for (j, b) in b.iter().enumerate() {
+                let substitution = previous[j] + usize::from(a != b);
+                current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+            }
+            std::mem::swap(&mut previous, &mut current);
+        }
+        self.stack.push(previous[b.len()]);
+    }
+
+    fn crc32(&mut self) {
+        let len = self.pop();
+        let address = self.pop();
+        let bytes = self.memory.get_range(address, len).expect("memory access out of bounds");
+        let mut crc = !0u32;
+        for byte in bytes {
+            crc ^= *byte as u32;
+            for _ in 0..8 {
+                crc = (crc >> 1) ^ (0xEDB88320 & (crc & 1).wrapping_neg());
+            }
+        }
+        self.stack.push(!crc as usize);
+    }
+
+    fn read_line(&mut self) {
+        let mut line = Vec::new();
+        while let Some(byte) = self.read_byte() {
+            line.push(byte);
+            if byte == b'\n' {
+                break;
+            }
+        }
+        let address = self.memory.extend(&line).expect("out of memory");
+        self.stack.push(address);
+        self.stack.push(line.len());
+    }
+
+    fn get_env(&mut self) {
+        let address = self.pop();
+        let name = self.memory.get_terminated(address).expect("memory access out of bounds");
+        match std::env::var(&*String::from_utf8_lossy(name)) {
+            Ok(value) => {
+                let address = self.memory.extend(value.as_bytes()).expect("out of memory");
+                self.stack.extend([address, value.len(), 1]);
+            }
+            Err(_) => self.stack.extend([0, 0, 0]),
+        }
+    }
+
+    fn format_base(&mut self) {
+        let base = self.pop();
+        let value = self.pop();
+        if !(2..=36).contains(&base) {
+            panic!("base {base} is not between 2 and 36");
+        }
+        let mut digits = Vec::new();
+        let mut rest = value;
+        loop {
+            digits.push(char::from_digit((rest % base) as u32, base as u32).unwrap() as u8);
+            rest /= base;
+            if rest == 0 {
+                break;
+            }
+        }
+        digits.reverse();
+        let address = self.memory.extend(&digits).expect("out of memory");
+        self.stack.push(address);
+        self.stack.push(digits.len());
+    }
+
+    fn byte_stack_push(&mut self) {
+        let value = self.pop();
+        let (base, capacity, len) = self.byte_stack;
+        if len == capacity {
+            panic!("byte stack of {capacity} bytes is full");
+        }
+        self.memory.set(base + len, value as u8).expect("memory access out of bounds");
+        self.byte_stack.2 += 1;
+    }
+
+    fn byte_stack_pop(&mut self) {
+        let (base, _, len) = self.byte_stack;
+        if len == 0 {
+            panic!("byte stack is empty");
+        }
+        let value = *self.memory.get(base + len - 1).expect("memory access out of bounds");
+        self.stack.push(value as usize);
+        self.byte_stack.2 -= 1;
+    }
+
+    fn rand(&mut self) {
+        self.seed = self.seed.wrapping_add(0x9E3779B97F4A7C15);
+        let mut value = self.seed;
+        value = (value ^ (value >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94D049BB133111EB);
+        self.stack.push((value ^ (value >> 31)) as usize);
+    }
+
+    fn end_redirect(&mut self) {
+        let output = self.captures.pop().expect("`endredirect` without a matching `redirect`");
+        let address = self.memory.extend(&output).expect("out of memory");
+        self.stack.push(address);
+        self.stack.push(output.len());
+    }
+}
+"##;
+
+impl Program {
+    // emits a standalone rust program with the same behaviour as interpreting `main`
+    pub fn compile_to_rust(&self) -> String {
+        let mut output =
+            String::from("// generated by stack_lang, compile with `rustc --edition 2021`\n");
+        output.push_str(PRELUDE);
+        output.push_str("\nmod memory {\n");
+        output.push_str(include_str!("memory.rs"));
+        output.push_str("}\n");
+
+        let leaving = leaving(&self.functions);
+        for name in self.functions.names() {
+            let leaves = self
+                .functions
+                .index(name)
+                .is_some_and(|index| leaving[index]);
+            let result = if leaves { " -> Leave" } else { "" };
+            output.push_str(&format!(
+                "\nfn {}(m: &mut Machine){result} {{\n",
+                function(name)
+            ));
+            for parameter in self.functions[name].parameters.iter().rev() {
+                output.push_str(&format!("    let mut {} = m.pop();\n", variable(parameter)));
+            }
+            let mut scope = Scope {
+                functions: &self.functions,
+                leaving: &leaving,
+                leaves,
+                loops: 0,
+            };
+            compile_segment(&self.functions[name].body, &mut scope, 1, &mut output);
+            if leaves {
+                output.push_str("    None\n");
+            }
+            output.push_str("}\n");
+        }
+
+        output.push_str(&format!(
+            r#"
+fn main() {{
+    let mut m = Machine {{
+        stack: Vec::with_capacity(1000),
+        frames: Vec::new(),
+        memory: Memory::new(),
+        output: std::io::stdout(),
+        input: std::io::stdin(),
+        captures: Vec::new(),
+        seed: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos() as u64),
+        byte_stack: (0, 0, 0),
+    }};
+    for size in {:?} {{
+        m.memory.alloc(size).expect("out of memory");
+    }}
+    {}(&mut m);
+    println!();
+}}
+"#,
+            self.globals,
+            function("main")
+        ));
+        output
+    }
+}
+
+// names in the source may contain any character, so everything but ascii alphanumerics is escaped
+fn mangle(name: &str) -> String {
+    name.chars()
+        .map(|char| match char {
+            char if char.is_ascii_alphanumeric() => char.to_string(),
+            char => format!("_{:x}_", char as u32),
+        })
+        .collect()
+}
+
+fn function(name: &str) -> String {
+    format!("f_{}", mangle(name))
+}
+
+fn variable(name: &str) -> String {
+    format!("v_{}", mangle(name))
+}
+
+fn loop_label(label: &Option<String>) -> String {
+    label
+        .as_ref()
+        .map_or(String::new(), |label| format!("'l_{}: ", mangle(label)))
+}
+
+// what the tokens of a function are compiled with
+struct Scope<'a> {
+    functions: &'a Functions,
+    leaving: &'a [bool], // whether each function returns a `Leave`
+    leaves: bool,        // whether the function being compiled returns a `Leave`
+    loops: usize,        // number of loops of the function around the token
+}
+
+// the functions with a `break` or `continue` outside of their loops, directly or through a call,
+// which has no label as labels only refer to loops of the same function
+fn leaving(functions: &Functions) -> Vec<bool> {
+    let mut leaving = vec![false; functions.len()];
+    loop {
+        let mut changed = false;
+        for (index, _, function) in functions.iter() {
+            if !leaving[index] && leaves(&function.body, &leaving) {
+                leaving[index] = true;
+                changed = true;
+            }
+        }
+        if !changed {
+            return leaving;
+        }
+    }
+}
+
+// loops catch every `break` and `continue` inside them
+fn leaves(segment: &[Token], leaving: &[bool]) -> bool {
+    segment.iter().any(|token| match token {
+        Token::Break(_) | Token::Continue(_) => true,
+        Token::FunctionCall(index) => leaving[*index],
+        Token::IfBlock(first, second) | Token::Conditional(first, second) => {
+            leaves(first, leaving) || leaves(second, leaving)
+        }
+        Token::MatchBlock(arms, default, _) => {
+            arms.iter().any(|(_, arm)| leaves(arm, leaving)) || leaves(default, leaving)
+        }
+        Token::LetBlock(segment, _) => leaves(segment, leaving),
+        Token::ForBlock(_, start, end, _) => leaves(start, leaving) || leaves(end, leaving),
+        _ => false,
+    })
+}
+
+fn compile_segment(segment: &[Token], scope: &mut Scope, depth: usize, output: &mut String) {
+    let indent = "    ".repeat(depth);
+    for token in segment {
+        let line = match token {
+            Token::Push(value) => format!("m.stack.push({value});"),
+            Token::Math(operand) => match operand {
+                MathOperator::Add => "m.binary(|a, b| a.wrapping_add(b));".to_string(),
+                MathOperator::Sub => "m.binary(|a, b| a.wrapping_sub(b));".to_string(),
+                MathOperator::Mul => "m.binary(|a, b| a.wrapping_mul(b));".to_string(),
+                MathOperator::Div => "m.binary(|a, b| a / b);".to_string(),
+                MathOperator::Mod => "m.binary(|a, b| a % b);".to_string(),
+            },
+            Token::Cmp(operand) => match operand {
+                CmpOperator::Less => "m.binary(|a, b| (a < b) as usize);".to_string(),
+                CmpOperator::Greater => "m.binary(|a, b| (a > b) as usize);".to_string(),
+                CmpOperator::Equal => "m.binary(|a, b| (a == b) as usize);".to_string(),
+            },
+            Token::Bit(operand) => match operand {
+                BitOperator::Shl => "m.shift(true);".to_string(),
+                BitOperator::Shr => "m.shift(false);".to_string(),
+                BitOperator::Not => "m.unary(|a| !a);".to_string(),
+                operand => format!("m.binary(|a, b| a {operand} b);"),
+            },
+            Token::Logic(operand) => match operand {
+                LogicOperator::And => "m.binary(|a, b| (a != 0 && b != 0) as usize);".to_string(),
+                LogicOperator::Or => "m.binary(|a, b| (a != 0 || b != 0) as usize);".to_string(),
+                LogicOperator::Not => "m.unary(|a| (a == 0) as usize);".to_string(),
+            },
+            Token::Char(operand) => {
+                let operation = match operand {
+                    CharOperation::IsDigit => "a.is_ascii_digit() as usize",
+                    CharOperation::IsAlpha => "a.is_ascii_alphabetic() as usize",
+                    CharOperation::IsSpace => "a.is_ascii_whitespace() as usize",
+                    CharOperation::ToUpper => "a.to_ascii_uppercase() as usize",
+                    CharOperation::ToLower => "a.to_ascii_lowercase() as usize",
+                };
+                let fallback = match operand {
+                    CharOperation::ToUpper | CharOperation::ToLower => "value",
+                    _ => "0",
+                };
+                format!("m.unary(|value| u8::try_from(value).map_or({fallback}, |a| {operation}));")
+            }
+            Token::Stack(operand) => match operand {
+                StackOperation::Dup => {
+                    "let a = m.pop(); m.stack.push(a); m.stack.push(a);".to_string()
+                }
+                StackOperation::Swap => {
+                    "let a = m.pop(); let b = m.pop(); m.stack.push(a); m.stack.push(b);"
+                        .to_string()
+                }
+                StackOperation::Over => {
+                    "let a = m.pop(); let b = m.pop(); m.stack.extend([b, a, b]);".to_string()
+                }
+                StackOperation::Rot => {
+                    "let a = m.pop(); let b = m.pop(); let c = m.pop(); m.stack.extend([b, a, c]);"
+                        .to_string()
+                }
+                StackOperation::Drop => "m.pop();".to_string(),
+                StackOperation::Nip => "let a = m.pop(); m.pop(); m.stack.push(a);".to_string(),
+                StackOperation::Tuck => {
+                    "let a = m.pop(); let b = m.pop(); m.stack.extend([a, b, a]);".to_string()
+                }
+                StackOperation::TwoDrop => "m.pop(); m.pop();".to_string(),
+                StackOperation::Pick => "m.pick();".to_string(),
+                StackOperation::TwoDup => {
+                    "let a = m.pop(); let b = m.pop(); m.stack.extend([b, a, b, a]);".to_string()
+                }
+                StackOperation::PushFrame => "m.push_frame();".to_string(),
+                StackOperation::PopFrame => {
+                    "m.frames.pop().expect(\"`popframe` without a matching `pushframe`\");"
+                        .to_string()
+                }
+                StackOperation::Depth => {
+                    "let depth = m.stack.len() - m.frames.last().copied().unwrap_or(0); m.stack.push(depth);"
+                        .to_string()
+                }
+            },
+            Token::Memory(operand) => match operand {
+                MemoryOperation::PushBytes(data) => {
+                    format!("let address = m.memory.extend(&{data:?}).expect(\"out of memory\"); m.stack.push(address);")
+                }
+                MemoryOperation::StoreByte => "m.store_byte();".to_string(),
+                MemoryOperation::LoadByte => "m.load_byte();".to_string(),
+                MemoryOperation::StoreWord => "m.store_word();".to_string(),
+                MemoryOperation::LoadWord => "m.load_word();".to_string(),
+                MemoryOperation::Free => {
+                    "let len = m.pop(); let address = m.pop(); m.memory.remove(address, len).expect(\"invalid free\");"
+                        .to_string()
+                }
+                MemoryOperation::Alloc => {
+                    "let len = m.pop(); let address = m.memory.alloc(len).expect(\"out of memory\"); m.stack.push(address);"
+                        .to_string()
+                }
+                MemoryOperation::Here => {
+                    "let address = m.memory.here(); m.stack.push(address);".to_string()
+                }
+                MemoryOperation::CountedAlloc => {
+                    "let len = m.pop(); let handle = m.memory.alloc_counted(len).expect(\"out of memory\"); m.stack.push(handle);"
+                        .to_string()
+                }
+                MemoryOperation::CountedAddress => {
+                    "let handle = m.pop(); let address = m.memory.counted_address(handle).expect(\"invalid handle\"); m.stack.push(address);"
+                        .to_string()
+                }
+                MemoryOperation::IncrementReferences => {
+                    "let handle = m.pop(); m.memory.increment_references(handle).expect(\"invalid handle\");"
+                        .to_string()
+                }
+                MemoryOperation::DecrementReferences => {
+                    "let handle = m.pop(); m.memory.decrement_references(handle).expect(\"invalid handle\");"
+                        .to_string()
+                }
+                MemoryOperation::ByteStackInit => {
+                    "let capacity = m.pop(); let base = m.pop(); m.byte_stack = (base, capacity, 0);"
+                        .to_string()
+                }
+                MemoryOperation::ByteStackPush => "m.byte_stack_push();".to_string(),
+                MemoryOperation::ByteStackPop => "m.byte_stack_pop();".to_string(),
+                MemoryOperation::MapNew => {
+                    "let table = m.memory.map_new().expect(\"out of memory\"); m.stack.push(table);"
+                        .to_string()
+                }
+                MemoryOperation::MapSet => {
+                    "let key = m.pop(); let value = m.pop(); let table = m.pop(); m.memory.map_set(table, key, value).unwrap();"
+                        .to_string()
+                }
+                MemoryOperation::MapGet => {
+                    "let key = m.pop(); let table = m.pop(); let value = m.memory.map_get(table, key).unwrap(); m.stack.push(value.unwrap_or(0)); m.stack.push(value.is_some() as usize);"
+                        .to_string()
+                }
+                MemoryOperation::Copy => "m.copy();".to_string(),
+                MemoryOperation::Fill => "m.fill();".to_string(),
+                MemoryOperation::EditDistance => "m.edit_distance();".to_string(),
+                MemoryOperation::FormatBase => "m.format_base();".to_string(),
+                MemoryOperation::Crc32 => "m.crc32();".to_string(),
+                MemoryOperation::Increment => "m.step_word(true);".to_string(),
+                MemoryOperation::Decrement => "m.step_word(false);".to_string(),
+                MemoryOperation::CrlfToLf => "m.crlf_to_lf();".to_string(),
+                MemoryOperation::Histogram => "m.histogram();".to_string(),
+            },
+            Token::FunctionCall(index) if !scope.leaving[*index] => {
+                format!("{}(m);", function(scope.functions.name(*index)))
+            }
+            Token::FunctionCall(index) if scope.loops == 0 => format!(
+                "if let leave @ Some(_) = {}(m) {{ return leave; }}",
+                function(scope.functions.name(*index))
+            ),
+            Token::FunctionCall(index) => {
+                let call = function(scope.functions.name(*index));
+                output.push_str(&format!("{indent}match {call}(m) {{\n"));
+                output.push_str(&format!("{indent}    Some(false) => break,\n"));
+                output.push_str(&format!("{indent}    Some(true) => continue,\n"));
+                output.push_str(&format!("{indent}    None => {{}}\n"));
+                "}".to_string()
+            }
+            Token::IfBlock(true_block, false_block) | Token::Conditional(true_block, false_block) => {
+                output.push_str(&format!("{indent}if m.pop() != 0 {{\n"));
+                compile_segment(true_block, scope, depth + 1, output);
+                output.push_str(&format!("{indent}}} else {{\n"));
+                compile_segment(false_block, scope, depth + 1, output);
+                "}".to_string()
+            }
+            Token::MatchBlock(arms, default, _) => {
+                output.push_str(&format!("{indent}match m.pop() {{\n"));
+                for (label, arm) in arms {
+                    output.push_str(&format!("{indent}    {label} => {{\n"));
+                    compile_segment(arm, scope, depth + 2, output);
+                    output.push_str(&format!("{indent}    }}\n"));
+                }
+                output.push_str(&format!("{indent}    _ => {{\n"));
+                compile_segment(default, scope, depth + 2, output);
+                output.push_str(&format!("{indent}    }}\n"));
+                "}".to_string()
+            }
+            Token::LoopBlock(segment, label) => {
+                output.push_str(&format!("{indent}{}loop {{\n", loop_label(label)));
+                scope.loops += 1;
+                compile_segment(segment, scope, depth + 1, output);
+                scope.loops -= 1;
+                "}".to_string()
+            }
+            Token::WhileBlock(condition, segment, label) => {
+                output.push_str(&format!("{indent}{}loop {{\n", loop_label(label)));
+                scope.loops += 1;
+                compile_segment(condition, scope, depth + 1, output);
+                output.push_str(&format!("{indent}    if m.pop() == 0 {{\n"));
+                output.push_str(&format!("{indent}        break;\n"));
+                output.push_str(&format!("{indent}    }}\n"));
+                compile_segment(segment, scope, depth + 1, output);
+                scope.loops -= 1;
+                "}".to_string()
+            }
+            Token::ForBlock(counter, start, end, segment) => {
+                output.push_str(&format!("{indent}{{\n"));
+                compile_segment(start, scope, depth + 1, output);
+                compile_segment(end, scope, depth + 1, output);
+                output.push_str(&format!("{indent}    let end = m.pop();\n"));
+                output.push_str(&format!("{indent}    let start = m.pop();\n"));
+                output.push_str(&format!(
+                    "{indent}    for mut {} in start..end {{\n",
+                    variable(counter)
+                ));
+                scope.loops += 1;
+                compile_segment(segment, scope, depth + 2, output);
+                scope.loops -= 1;
+                output.push_str(&format!("{indent}    }}\n"));
+                "}".to_string()
+            }
+            Token::RepeatBlock(segment) => {
+                output.push_str(&format!("{indent}for _ in 0..m.pop() {{\n"));
+                scope.loops += 1;
+                compile_segment(segment, scope, depth + 1, output);
+                scope.loops -= 1;
+                "}".to_string()
+            }
+            Token::Break(label) | Token::Continue(label) => {
+                let is_continue = matches!(token, Token::Continue(_));
+                let keyword = if is_continue { "continue" } else { "break" };
+                match label {
+                    _ if scope.loops == 0 => format!("return Some({is_continue});"),
+                    Some(label) => format!("{keyword} 'l_{};", mangle(label)),
+                    None => format!("{keyword};"),
+                }
+            }
+            Token::Return if scope.leaves => "return None;".to_string(),
+            Token::Return => "return;".to_string(),
+            // the compiled program runs alone, so there is no host to hand control to
+            Token::Yield => "// yield".to_string(),
+            Token::LetBlock(segment, let_bindings) => {
+                output.push_str(&format!("{indent}{{\n"));
+                for let_binding in let_bindings {
+                    output.push_str(&format!(
+                        "{indent}    let mut {} = m.pop();\n",
+                        variable(let_binding)
+                    ));
+                }
+                compile_segment(segment, scope, depth + 1, output);
+                "}".to_string()
+            }
+            Token::Let(let_binding) => format!("m.stack.push({});", variable(let_binding)),
+            Token::SetLet(let_binding) => format!("{} = m.pop();", variable(let_binding)),
+            Token::Putc => {
+                "let a = m.pop(); m.write(&char::from_u32(a as u32).unwrap().to_string());"
+                    .to_string()
+            }
+            Token::Putu => "let a = m.pop(); m.write(&a.to_string());".to_string(),
+            Token::Puti => "let a = m.pop(); m.write(&(a as isize).to_string());".to_string(),
+            Token::Puts => "m.puts();".to_string(),
+            Token::Redirect => "m.captures.push(Vec::new());".to_string(),
+            Token::EndRedirect => "m.end_redirect();".to_string(),
+            Token::Getc => {
+                "let byte = m.read_byte(); m.stack.push(byte.map_or(usize::MAX, usize::from));"
+                    .to_string()
+            }
+            Token::Getu => "m.getu();".to_string(),
+            Token::Readline => "m.read_line();".to_string(),
+            Token::Getenv => "m.get_env();".to_string(),
+            Token::Rand => "m.rand();".to_string(),
+            Token::Seed => "m.seed = m.pop() as u64;".to_string(),
+            Token::GetSeed => "m.stack.push(m.seed as usize);".to_string(),
+            Token::Debug => {
+                "let debug = format!(\"{:?} {:?}\\n\", m.stack, m.memory); m.write(&debug);"
+                    .to_string()
+            }
+        };
+        output.push_str(&format!("{indent}{line}\n"));
+    }
+}
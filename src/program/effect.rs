@@ -0,0 +1,242 @@
+use super::{Functions, Program};
+use crate::token::*;
+use std::collections::HashMap;
+
+// how a segment changes the number of values on the stack, when that is known before running it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Effect {
+    Net(isize),
+    Diverges, // always leaves the segment through `break`, `continue` or `return`
+}
+
+struct Analysis<'a> {
+    functions: &'a Functions,
+    visiting: Vec<usize>, // functions whose effect is being computed, calling them again is recursion
+    returns: Vec<isize>,  // heights of the stack at every `return` of the body being analysed
+}
+
+impl Program {
+    // the net effect of a function body, not counting the parameters popped before it runs,
+    // None when it depends on the values or recursion
+    pub(super) fn body_effect(functions: &Functions, body: &[Token]) -> Option<isize> {
+        Analysis {
+            functions,
+            visiting: Vec::new(),
+            returns: Vec::new(),
+        }
+        .body_effect(body)
+    }
+
+    // the net effect of calling each function, parameters included, None when it can't be determined
+    pub fn stack_effects(&self) -> HashMap<String, Option<isize>> {
+        let mut analysis = Analysis {
+            functions: &self.functions,
+            visiting: Vec::new(),
+            returns: Vec::new(),
+        };
+        self.functions
+            .iter()
+            .map(|(index, name, _)| (name.to_string(), analysis.function_effect(index)))
+            .collect()
+    }
+}
+
+impl Analysis<'_> {
+    fn function_effect(&mut self, index: usize) -> Option<isize> {
+        let function = self.functions.get_index(index)?;
+        let parameters = function.parameters.len() as isize;
+        if let Some(results) = &function.results {
+            return Some(results.len() as isize - parameters);
+        }
+        if self.visiting.contains(&index) {
+            return None;
+        }
+        self.visiting.push(index);
+        let effect = self.body_effect(&function.body);
+        self.visiting.pop();
+        Some(effect? - parameters)
+    }
+
+    // every way out of the body has to leave the same number of values
+    fn body_effect(&mut self, body: &[Token]) -> Option<isize> {
+        let outer_returns = std::mem::take(&mut self.returns);
+        let effect = self.segment_effect(body, 0, &[]);
+        let returns = std::mem::replace(&mut self.returns, outer_returns);
+        let mut merged = match effect? {
+            Effect::Net(effect) => Some(effect),
+            // a break outside of any loop of the function leaves the caller's loop
+            Effect::Diverges => None,
+        };
+        for height in returns {
+            match merged {
+                Some(effect) if effect != height => return None,
+                _ => merged = Some(height),
+            }
+        }
+        merged
+    }
+
+    // `height` is the number of values the function has left on the stack before the segment,
+    // `loops` has the labels of the loops around it and the heights at the start of their iterations,
+    // which every `break` and `continue` has to leave unchanged for the loop to have a known effect
+    fn segment_effect(
+        &mut self,
+        segment: &[Token],
+        height: isize,
+        loops: &[(Option<&str>, isize)],
+    ) -> Option<Effect> {
+        let mut effect = 0;
+        for token in segment {
+            let height = height + effect;
+            let token_effect = match token {
+                Token::Push(_) | Token::Let(_) => 1,
+                Token::Math(_) | Token::Cmp(_) => -1,
+                Token::Bit(BitOperator::Not) | Token::Logic(LogicOperator::Not) => 0,
+                Token::Bit(_) | Token::Logic(_) => -1,
+                Token::Char(_) => 0,
+                Token::Stack(operation) => match operation {
+                    StackOperation::Dup
+                    | StackOperation::Over
+                    | StackOperation::Tuck
+                    | StackOperation::Depth => 1,
+                    StackOperation::TwoDup => 2,
+                    StackOperation::Swap
+                    | StackOperation::Rot
+                    | StackOperation::Pick
+                    | StackOperation::PopFrame => 0,
+                    StackOperation::Drop | StackOperation::Nip | StackOperation::PushFrame => -1,
+                    StackOperation::TwoDrop => -2,
+                },
+                Token::Memory(operation) => match operation {
+                    MemoryOperation::PushBytes(_)
+                    | MemoryOperation::Here
+                    | MemoryOperation::MapNew => 1,
+                    MemoryOperation::LoadByte
+                    | MemoryOperation::LoadWord
+                    | MemoryOperation::Alloc
+                    | MemoryOperation::CountedAlloc
+                    | MemoryOperation::CountedAddress
+                    | MemoryOperation::Increment
+                    | MemoryOperation::Decrement
+                    | MemoryOperation::FormatBase
+                    | MemoryOperation::MapGet => 0,
+                    MemoryOperation::ByteStackPop => 1,
+                    MemoryOperation::CrlfToLf
+                    | MemoryOperation::Crc32
+                    | MemoryOperation::ByteStackPush
+                    | MemoryOperation::IncrementReferences
+                    | MemoryOperation::DecrementReferences => -1,
+                    MemoryOperation::StoreByte
+                    | MemoryOperation::StoreWord
+                    | MemoryOperation::ByteStackInit
+                    | MemoryOperation::Free => -2,
+                    MemoryOperation::Copy
+                    | MemoryOperation::MapSet
+                    | MemoryOperation::Fill
+                    | MemoryOperation::EditDistance
+                    | MemoryOperation::Histogram => -3,
+                },
+                Token::FunctionCall(index) => self.function_effect(*index)?,
+                Token::IfBlock(true_block, false_block)
+                | Token::Conditional(true_block, false_block) => {
+                    match self.branches_effect([true_block, false_block], height, 1, loops)? {
+                        Effect::Net(effect) => effect,
+                        Effect::Diverges => return Some(Effect::Diverges),
+                    }
+                }
+                Token::MatchBlock(arms, default, _) => {
+                    let segments = arms.iter().map(|(_, arm)| arm).chain([default]);
+                    match self.branches_effect(segments, height, 1, loops)? {
+                        Effect::Net(effect) => effect,
+                        Effect::Diverges => return Some(Effect::Diverges),
+                    }
+                }
+                Token::LetBlock(body, bindings) => {
+                    let popped = bindings.len() as isize;
+                    match self.branches_effect([body], height, popped, loops)? {
+                        Effect::Net(effect) => effect,
+                        Effect::Diverges => return Some(Effect::Diverges),
+                    }
+                }
+                // the loops run any number of times, so their bodies must leave the stack as they found it
+                Token::LoopBlock(body, label) => {
+                    self.loop_body_effect(body, height, label, loops)?;
+                    0
+                }
+                Token::WhileBlock(condition, body, label) => {
+                    (self.segment_effect(condition, height, &[])? == Effect::Net(1))
+                        .then_some(())?;
+                    self.loop_body_effect(body, height, label, loops)?;
+                    0
+                }
+                Token::ForBlock(_, start, end, body) => {
+                    for bound in [start, end] {
+                        (self.segment_effect(bound, height, &[])? == Effect::Net(1))
+                            .then_some(())?;
+                    }
+                    self.loop_body_effect(body, height, &None, loops)?;
+                    0
+                }
+                Token::RepeatBlock(body) => {
+                    self.loop_body_effect(body, height - 1, &None, loops)?;
+                    -1
+                }
+                Token::Break(label) | Token::Continue(label) => {
+                    let (_, loop_height) = match label {
+                        Some(label) => loops.iter().rev().find(|(own, _)| *own == Some(label))?,
+                        None => loops.last()?,
+                    };
+                    return (*loop_height == height).then_some(Effect::Diverges);
+                }
+                Token::Return => {
+                    self.returns.push(height);
+                    return Some(Effect::Diverges);
+                }
+                Token::Putc | Token::Putu | Token::Puti | Token::Puts | Token::SetLet(_) => -1,
+                Token::Redirect | Token::Debug | Token::Yield => 0,
+                Token::EndRedirect | Token::Readline | Token::Getenv => 2,
+                Token::Getc | Token::Getu | Token::Rand | Token::GetSeed => 1,
+                Token::Seed => -1,
+            };
+            effect += token_effect;
+        }
+        Some(Effect::Net(effect))
+    }
+
+    // the effect shared by all of the segments that don't diverge, after popping the values they are selected by
+    fn branches_effect<'s>(
+        &mut self,
+        segments: impl IntoIterator<Item = &'s Vec<Token>>,
+        height: isize,
+        popped: isize,
+        loops: &[(Option<&str>, isize)],
+    ) -> Option<Effect> {
+        let mut merged = Effect::Diverges;
+        for segment in segments {
+            match self.segment_effect(segment, height - popped, loops)? {
+                Effect::Diverges => {}
+                Effect::Net(effect) if merged == Effect::Diverges => merged = Effect::Net(effect),
+                branch => (branch == merged).then_some(())?,
+            }
+        }
+        Some(match merged {
+            Effect::Net(effect) => Effect::Net(effect - popped),
+            Effect::Diverges => Effect::Diverges,
+        })
+    }
+
+    fn loop_body_effect(
+        &mut self,
+        body: &[Token],
+        height: isize,
+        label: &Option<String>,
+        loops: &[(Option<&str>, isize)],
+    ) -> Option<()> {
+        let mut loops = loops.to_vec();
+        loops.push((label.as_deref(), height));
+        match self.segment_effect(body, height, &loops)? {
+            Effect::Net(0) | Effect::Diverges => Some(()),
+            Effect::Net(_) => None,
+        }
+    }
+}
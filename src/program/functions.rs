@@ -0,0 +1,213 @@
+use super::Function;
+use crate::token::Token;
+use std::{collections::HashMap, ops::Index};
+
+// the functions of a program, calls refer to them by index so that running a call needs no lookup by name
+#[derive(Debug, Default)]
+pub(super) struct Functions {
+    functions: Vec<Option<Function>>, // None for a name that is called before a function with it is defined
+    names: Vec<String>,
+    indices: HashMap<String, usize>,
+}
+
+impl Functions {
+    pub fn len(&self) -> usize {
+        self.functions.len()
+    }
+
+    pub fn index(&self, name: &str) -> Option<usize> {
+        self.indices.get(name).copied()
+    }
+
+    // empty for an index that was never reserved
+    pub fn name(&self, index: usize) -> &str {
+        self.names.get(index).map_or("", String::as_str)
+    }
+
+    // None when no function with the index is defined
+    pub fn get_index(&self, index: usize) -> Option<&Function> {
+        self.functions.get(index)?.as_ref()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Function> {
+        self.get_index(self.index(name)?)
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut Function> {
+        self.functions.get_mut(index)?.as_mut()
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    // the index calls to the name refer to, reserved for it when no function with the name is defined yet
+    pub fn reserve(&mut self, name: &str) -> usize {
+        if let Some(index) = self.index(name) {
+            return index;
+        }
+        self.functions.push(None);
+        self.names.push(name.to_string());
+        self.indices.insert(name.to_string(), self.len() - 1);
+        self.len() - 1
+    }
+
+    pub fn insert(&mut self, name: &str, function: Function) -> usize {
+        let index = self.reserve(name);
+        self.functions[index] = Some(function);
+        index
+    }
+
+    // the token as written in the source, so a call shows the name of the function
+    pub fn display(&self, token: &Token) -> String {
+        match token {
+            Token::FunctionCall(index) => self.name(*index).to_string(),
+            token => token.to_string(),
+        }
+    }
+
+    // the defined functions with their indices and names
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &str, &Function)> {
+        self.functions
+            .iter()
+            .enumerate()
+            .filter_map(|(index, function)| Some((index, self.name(index), function.as_ref()?)))
+    }
+
+    // names of the defined functions, sorted
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.iter().map(|(_, name, _)| name).collect();
+        names.sort_unstable();
+        names
+    }
+
+    // removes the functions reserved from the index on, which nothing before them may call
+    pub fn truncate(&mut self, len: usize) {
+        for name in self.names.drain(len.min(self.names.len())..) {
+            self.indices.remove(&name);
+        }
+        self.functions.truncate(len);
+    }
+
+    // points the calls to the functions that are keys of the map at the functions they map to
+    pub fn redirect(&mut self, redirects: &HashMap<usize, usize>) {
+        if redirects.is_empty() {
+            return;
+        }
+        for function in self.functions.iter_mut().flatten() {
+            renumber_calls(&mut function.body, &|index| {
+                redirects.get(&index).copied().unwrap_or(index)
+            });
+        }
+    }
+
+    // removes the functions the predicate is false for, renumbering the calls to the others.
+    // the functions that are kept may not call the removed ones
+    pub fn retain(&mut self, mut keep: impl FnMut(&str) -> bool) {
+        let mut kept = Functions::default();
+        let mut indices = vec![usize::MAX; self.len()];
+        for (index, (function, name)) in self
+            .functions
+            .drain(..)
+            .zip(self.names.drain(..))
+            .enumerate()
+        {
+            if let Some(function) = function.filter(|_| keep(&name)) {
+                indices[index] = kept.insert(&name, function);
+            }
+        }
+        for function in kept.functions.iter_mut().flatten() {
+            renumber_calls(&mut function.body, &|index| indices[index]);
+        }
+        *self = kept;
+    }
+
+    // adds the functions after the ones already there, none of them may have the name of one of these.
+    // names reserved by either are shared, so calls reserved before a function is defined refer to it
+    pub fn append(&mut self, other: Functions) {
+        let indices: Vec<usize> = other.names.iter().map(|name| self.reserve(name)).collect();
+        for (index, function) in indices.iter().zip(other.functions) {
+            if let Some(mut function) = function {
+                assert!(
+                    self.functions[*index].is_none(),
+                    "function `{}` is defined twice",
+                    self.names[*index]
+                );
+                renumber_calls(&mut function.body, &|call| indices[call]);
+                self.functions[*index] = Some(function);
+            }
+        }
+    }
+}
+
+// a call to an undefined function can only be in a program that failed to parse
+impl Index<usize> for Functions {
+    type Output = Function;
+
+    fn index(&self, index: usize) -> &Function {
+        self.get_index(index)
+            .expect("call to an undefined function")
+    }
+}
+
+impl Index<&str> for Functions {
+    type Output = Function;
+
+    fn index(&self, name: &str) -> &Function {
+        self.get(name)
+            .expect("no function with the name is defined")
+    }
+}
+
+fn renumber_calls(segment: &mut [Token], renumber: &impl Fn(usize) -> usize) {
+    for token in segment {
+        match token {
+            Token::FunctionCall(index) => *index = renumber(*index),
+            Token::IfBlock(first, second)
+            | Token::Conditional(first, second)
+            | Token::WhileBlock(first, second, _) => {
+                renumber_calls(first, renumber);
+                renumber_calls(second, renumber);
+            }
+            Token::LoopBlock(segment, _)
+            | Token::RepeatBlock(segment)
+            | Token::LetBlock(segment, _) => renumber_calls(segment, renumber),
+            Token::ForBlock(_, start, end, segment) => {
+                for segment in [start, end, segment] {
+                    renumber_calls(segment, renumber);
+                }
+            }
+            Token::MatchBlock(arms, default, _) => {
+                for (_, arm) in arms {
+                    renumber_calls(arm, renumber);
+                }
+                renumber_calls(default, renumber);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn test_append() {
+    let function = |body| Function {
+        parameters: Vec::new(),
+        pure: false,
+        results: None,
+        body,
+    };
+    let mut functions = Functions::default();
+    let later = functions.reserve("later");
+    functions.insert("main", function(vec![Token::FunctionCall(later)]));
+    let mut other = Functions::default();
+    let main = other.reserve("main");
+    other.insert("helper", function(vec![]));
+    other.insert("later", function(vec![Token::FunctionCall(main)]));
+    functions.append(other);
+    // the calls reserved by either refer to the functions the other defines
+    assert_eq!(functions.index("later"), Some(later));
+    let main = functions.index("main").unwrap();
+    assert!(matches!(functions[later].body[..], [Token::FunctionCall(call)] if call == main));
+    assert!(matches!(functions[main].body[..], [Token::FunctionCall(call)] if call == later));
+    assert_eq!(functions.names(), ["helper", "later", "main"]);
+}
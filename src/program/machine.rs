@@ -0,0 +1,1033 @@
+use super::{
+    crc32, edit_distance, format_base, memory::WORD_SIZE, next_random, Config, Function, Memory,
+    Program, RuntimeError, Stack, EDIT_DISTANCE_LIMIT, RECURSION_LIMIT,
+};
+use crate::io::Io;
+use crate::token::*;
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepResult {
+    Running,
+    Yielded, // the step executed a `yield`, the host can run something else before stepping again
+    Finished, // the entry point returned, further steps do nothing
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ForStage {
+    Start,
+    End,
+    Body {
+        value: usize,
+        next: usize,
+        end: usize,
+    }, // the value is bound to the variable, assigning it doesn't change the next one
+}
+
+#[derive(Debug)]
+enum FrameKind<'p> {
+    Call(HashMap<&'p str, usize>), // the parameters, bindings of the caller are not visible past it
+    Block,                         // a branch of `if` or `match`
+    Let(HashMap<&'p str, usize>),
+    Loop(Option<&'p str>),
+    While {
+        token: &'p Token,
+        label: Option<&'p str>,
+        condition: &'p [Token],
+        body: &'p [Token],
+        in_condition: bool,
+    },
+    For {
+        token: &'p Token,
+        variable: &'p str,
+        end: &'p [Token],
+        body: &'p [Token],
+        stage: ForStage,
+    },
+}
+
+// a segment that is being interpreted, with the index of the next token in it
+#[derive(Debug)]
+struct Frame<'p> {
+    segment: &'p [Token],
+    ip: usize,
+    kind: FrameKind<'p>,
+}
+
+// the state of a running program, which is advanced a single token at a time
+pub struct Machine<'p> {
+    program: &'p Program,
+    stack: Stack,
+    memory: Memory,
+    frames: Vec<Frame<'p>>, // innermost last, the run is finished once it is empty
+    calls: usize,           // number of call frames
+    seed: u64,              // state of the random generator
+    byte_stack: ByteStack,
+    yielded: bool, // set by `yield` until the step executing it returns
+}
+
+// a stack of bytes kept in a region of memory chosen by the program, empty with no capacity until then
+#[derive(Debug, Default)]
+struct ByteStack {
+    base: usize,
+    capacity: usize,
+    len: usize,
+}
+
+impl<'p> Machine<'p> {
+    // allocates the globals and calls the entry point, binding its parameters
+    pub fn new(program: &'p Program, config: &mut Config) -> Result<Self, RuntimeError> {
+        Self::with_stack(program, Stack::new(), config)
+    }
+
+    pub(super) fn with_stack(
+        program: &'p Program,
+        stack: Stack,
+        config: &mut Config,
+    ) -> Result<Self, RuntimeError> {
+        let memory = Self::globals(program, config)?;
+        Self::with_state(program, stack, memory, config)
+    }
+
+    // memory with the globals of the program allocated, before anything else is
+    pub(super) fn globals(program: &Program, config: &Config) -> Result<Memory, RuntimeError> {
+        let mut memory = config
+            .memory_limit
+            .map_or_else(Memory::new, Memory::with_limit);
+        for size in &program.globals {
+            memory.alloc(*size).map_err(RuntimeError::OutOfMemory)?;
+        }
+        Ok(memory)
+    }
+
+    // continues from the stack and memory left by an earlier run, whose globals are already allocated
+    pub(super) fn with_state(
+        program: &'p Program,
+        stack: Stack,
+        memory: Memory,
+        config: &mut Config,
+    ) -> Result<Self, RuntimeError> {
+        let entry_point = config.entry_point.unwrap_or("main");
+        let Some(index) = program
+            .functions
+            .index(entry_point)
+            .filter(|&index| program.functions.get_index(index).is_some())
+        else {
+            return Err(RuntimeError::MissingEntryPoint(entry_point.to_string()));
+        };
+        let mut machine = Self::idle(program, stack, memory, config);
+        machine.call(index, config)?;
+        machine.resolve(config)?;
+        Ok(machine)
+    }
+
+    // a machine with nothing to run, for running tokens that are not taken from its frames
+    pub(super) fn idle(
+        program: &'p Program,
+        stack: Stack,
+        memory: Memory,
+        config: &Config,
+    ) -> Self {
+        Self {
+            program,
+            stack,
+            memory,
+            frames: Vec::new(),
+            calls: 0,
+            seed: config.seed.map_or_else(clock_seed, |seed| seed as u64),
+            byte_stack: ByteStack::default(),
+            yielded: false,
+        }
+    }
+
+    // all of the values, including the ones hidden by frames
+    pub fn stack(&self) -> &[usize] {
+        self.stack.values()
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    // the token the next step executes, None once the run is finished
+    pub fn next_token(&self) -> Option<&'p Token> {
+        let frame = self.frames.last()?;
+        Some(&frame.segment[frame.ip])
+    }
+
+    // index of the next token in the innermost segment being interpreted
+    pub fn instruction_pointer(&self) -> Option<usize> {
+        self.frames.last().map(|frame| frame.ip)
+    }
+
+    // number of functions that have been called and have not returned yet
+    pub fn call_depth(&self) -> usize {
+        self.calls
+    }
+
+    // the state of the random generator, which `seed` accepts to repeat the values from this point
+    pub fn seed(&self) -> usize {
+        self.seed as usize
+    }
+
+    pub(super) fn into_stack(self) -> Stack {
+        self.stack
+    }
+
+    pub(super) fn into_state(self) -> (Stack, Memory) {
+        (self.stack, self.memory)
+    }
+
+    // executes the next token, unless the hook skips it
+    pub fn step<W: Write, R: Read>(
+        &mut self,
+        io: &mut Io<W, R>,
+        config: &mut Config,
+    ) -> Result<StepResult, RuntimeError> {
+        let Some(frame) = self.frames.last_mut() else {
+            return Ok(StepResult::Finished);
+        };
+        let segment = frame.segment;
+        let token = &segment[frame.ip];
+        frame.ip += 1;
+        let skipped = config
+            .hook
+            .as_deref_mut()
+            .is_some_and(|hook| !hook(token, self.stack.values()));
+        if !skipped {
+            if let Some(stats) = config.stats.as_deref_mut() {
+                stats.token(self.stack.depth());
+            }
+            self.execute(token, io, config)?;
+            self.check_limits(config)?;
+        }
+        self.resolve(config)?;
+        let yielded = std::mem::take(&mut self.yielded);
+        Ok(if self.frames.is_empty() {
+            StepResult::Finished
+        } else if yielded {
+            StepResult::Yielded
+        } else {
+            StepResult::Running
+        })
+    }
+
+    // steps until the next `yield` or the end of the run, returning which of them stopped it
+    pub fn resume<W: Write, R: Read>(
+        &mut self,
+        io: &mut Io<W, R>,
+        config: &mut Config,
+    ) -> Result<StepResult, RuntimeError> {
+        loop {
+            match self.step(io, config)? {
+                StepResult::Running => {}
+                result => return Ok(result),
+            }
+        }
+    }
+
+    pub(super) fn check_limits(&self, config: &Config) -> Result<(), RuntimeError> {
+        if let Some(limit) = config.allocation_limit {
+            if self.memory.allocations() > self.program.globals.len() + limit {
+                return Err(RuntimeError::AllocationLimit(limit));
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn stack_mut(&mut self) -> &mut Stack {
+        &mut self.stack
+    }
+
+    fn call(&mut self, index: usize, config: &mut Config) -> Result<(), RuntimeError> {
+        let program = self.program;
+        let name = program.functions.name(index);
+        let function: &'p Function = program
+            .functions
+            .get_index(index)
+            .ok_or_else(|| RuntimeError::UnknownFunction(name.to_string()))?;
+        let limit = config.recursion_limit.unwrap_or(RECURSION_LIMIT);
+        if self.calls >= limit {
+            return Err(RuntimeError::RecursionLimit(limit));
+        }
+        let mut parameters = HashMap::new();
+        for parameter in function.parameters.iter().rev() {
+            let value = self
+                .stack
+                .pop()
+                .ok_or_else(|| RuntimeError::StackUnderflow(name.to_string()))?;
+            parameters.insert(parameter.as_str(), value);
+        }
+        if let Some(stats) = config.stats.as_deref_mut() {
+            stats.enter(name, self.stack.depth());
+        }
+        self.calls += 1;
+        self.push_frame(&function.body, FrameKind::Call(parameters));
+        Ok(())
+    }
+
+    fn push_frame(&mut self, segment: &'p [Token], kind: FrameKind<'p>) {
+        self.frames.push(Frame {
+            segment,
+            ip: 0,
+            kind,
+        });
+    }
+
+    // removes the innermost frame, returning from the function if it is a call
+    fn pop_frame(&mut self, config: &mut Config) -> Option<Frame<'p>> {
+        let frame = self.frames.pop()?;
+        if let FrameKind::Call(_) = frame.kind {
+            self.calls -= 1;
+            if let Some(stats) = config.stats.as_deref_mut() {
+                stats.exit(self.stack.depth());
+            }
+        }
+        Some(frame)
+    }
+
+    // moves past the segments that have ended, so that the innermost frame always has a next token
+    fn resolve(&mut self, config: &mut Config) -> Result<(), RuntimeError> {
+        while let Some(frame) = self.frames.last_mut() {
+            if frame.ip < frame.segment.len() {
+                break;
+            }
+            frame.ip = 0;
+            let keep = match &mut frame.kind {
+                FrameKind::Loop(_) => true,
+                FrameKind::Call(_) | FrameKind::Block | FrameKind::Let(_) => false,
+                FrameKind::While {
+                    token,
+                    condition,
+                    body,
+                    in_condition,
+                    ..
+                } => {
+                    if *in_condition {
+                        frame.segment = body;
+                        *in_condition = false;
+                        pop(&mut self.stack, token)? != 0
+                    } else {
+                        frame.segment = condition;
+                        *in_condition = true;
+                        true
+                    }
+                }
+                FrameKind::For {
+                    token,
+                    end,
+                    body,
+                    stage,
+                    ..
+                } => match *stage {
+                    ForStage::Start => {
+                        frame.segment = end;
+                        *stage = ForStage::End;
+                        true
+                    }
+                    ForStage::End => {
+                        let end = pop(&mut self.stack, token)?;
+                        let start = pop(&mut self.stack, token)?;
+                        frame.segment = body;
+                        *stage = ForStage::Body {
+                            value: start,
+                            next: start.wrapping_add(1),
+                            end,
+                        };
+                        start < end
+                    }
+                    ForStage::Body { next, end, .. } => {
+                        *stage = ForStage::Body {
+                            value: next,
+                            next: next.wrapping_add(1),
+                            end,
+                        };
+                        next < end
+                    }
+                },
+            };
+            if !keep {
+                self.pop_frame(config);
+            }
+        }
+        Ok(())
+    }
+
+    // the innermost binding with the name, looking no further than the function it is used in
+    fn variable(&self, name: &str) -> Option<usize> {
+        for frame in self.frames.iter().rev() {
+            match &frame.kind {
+                FrameKind::Call(variables) => return variables.get(name).copied(),
+                FrameKind::Let(variables) => {
+                    if let Some(value) = variables.get(name) {
+                        return Some(*value);
+                    }
+                }
+                FrameKind::For {
+                    variable,
+                    stage: ForStage::Body { value, .. },
+                    ..
+                } if *variable == name => return Some(*value),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    // assigns the innermost binding with the name, None when there is none like for `variable`
+    fn set_variable(&mut self, name: &str, new_value: usize) -> Option<()> {
+        for frame in self.frames.iter_mut().rev() {
+            let value = match &mut frame.kind {
+                FrameKind::Call(variables) => {
+                    return variables.get_mut(name).map(|value| *value = new_value)
+                }
+                FrameKind::Let(variables) => variables.get_mut(name),
+                FrameKind::For {
+                    variable,
+                    stage: ForStage::Body { value, .. },
+                    ..
+                } if *variable == name => Some(value),
+                _ => None,
+            };
+            if let Some(value) = value {
+                *value = new_value;
+                return Some(());
+            }
+        }
+        None
+    }
+
+    // leaves the innermost loop, a break outside of any loop ends the run
+    fn break_loop(&mut self, label: Option<&str>, config: &mut Config) {
+        while let Some(frame) = self.pop_frame(config) {
+            if frame.kind.is_loop(label) {
+                break;
+            }
+        }
+    }
+
+    // ends the current iteration of the innermost loop, a continue outside of any loop ends the run
+    fn continue_loop(&mut self, label: Option<&str>, config: &mut Config) {
+        while let Some(frame) = self.frames.last_mut() {
+            if frame.kind.is_loop(label) {
+                frame.ip = frame.segment.len();
+                break;
+            }
+            self.pop_frame(config);
+        }
+    }
+
+    // runs a single token, the blocks push the frames running their segments
+    pub(super) fn execute<W: Write, R: Read>(
+        &mut self,
+        token: &'p Token,
+        io: &mut Io<W, R>,
+        config: &mut Config,
+    ) -> Result<(), RuntimeError> {
+        let stack = &mut self.stack;
+        let memory = &mut self.memory;
+        match token {
+            Token::Push(value) => {
+                stack.push(*value);
+            }
+            Token::Math(operand) => {
+                let b = pop(stack, token)?;
+                let a = pop(stack, token)?;
+                let result = match operand {
+                    MathOperator::Add => a.wrapping_add(b),
+                    MathOperator::Sub => a.wrapping_sub(b),
+                    MathOperator::Mul => a.wrapping_mul(b),
+                    MathOperator::Div => a.checked_div(b).ok_or(RuntimeError::DivByZero)?,
+                    MathOperator::Mod => a.checked_rem(b).ok_or(RuntimeError::DivByZero)?,
+                };
+                stack.push(result);
+            }
+            Token::Cmp(operand) => {
+                let b = pop(stack, token)?;
+                let a = pop(stack, token)?;
+                let result = match operand {
+                    CmpOperator::Less => a < b,
+                    CmpOperator::Greater => a > b,
+                    CmpOperator::Equal => a == b,
+                };
+                stack.push(result as usize);
+            }
+            Token::Bit(BitOperator::Not) => {
+                let a = pop(stack, token)?;
+                stack.push(!a);
+            }
+            Token::Bit(operand) => {
+                let b = pop(stack, token)?;
+                let a = pop(stack, token)?;
+                let result = match operand {
+                    BitOperator::And => a & b,
+                    BitOperator::Or => a | b,
+                    BitOperator::Xor => a ^ b,
+                    BitOperator::Shl | BitOperator::Shr => {
+                        let amount = u32::try_from(b)
+                            .ok()
+                            .filter(|&amount| amount < usize::BITS)
+                            .ok_or(RuntimeError::ShiftOverflow(b))?;
+                        match operand {
+                            BitOperator::Shl => a << amount,
+                            _ => a >> amount,
+                        }
+                    }
+                    BitOperator::Not => unreachable!(),
+                };
+                stack.push(result);
+            }
+            Token::Logic(LogicOperator::Not) => {
+                let a = pop(stack, token)? != 0;
+                stack.push(!a as usize);
+            }
+            Token::Logic(operand) => {
+                let b = pop(stack, token)? != 0;
+                let a = pop(stack, token)? != 0;
+                let result = match operand {
+                    LogicOperator::And => a && b,
+                    LogicOperator::Or => a || b,
+                    LogicOperator::Not => unreachable!(),
+                };
+                stack.push(result as usize);
+            }
+            Token::Char(operand) => {
+                let value = pop(stack, token)?;
+                // values outside of the byte range are never ascii
+                let a = u8::try_from(value).ok();
+                let result = match operand {
+                    CharOperation::IsDigit => a.is_some_and(|a| a.is_ascii_digit()) as usize,
+                    CharOperation::IsAlpha => a.is_some_and(|a| a.is_ascii_alphabetic()) as usize,
+                    CharOperation::IsSpace => a.is_some_and(|a| a.is_ascii_whitespace()) as usize,
+                    CharOperation::ToUpper => a.map_or(value, |a| a.to_ascii_uppercase() as usize),
+                    CharOperation::ToLower => a.map_or(value, |a| a.to_ascii_lowercase() as usize),
+                };
+                stack.push(result);
+            }
+            Token::Stack(operand) => match operand {
+                StackOperation::Dup => {
+                    let a = pop(stack, token)?;
+                    stack.push(a);
+                    stack.push(a);
+                }
+                StackOperation::Swap => {
+                    let a = pop(stack, token)?;
+                    let b = pop(stack, token)?;
+                    stack.push(a);
+                    stack.push(b);
+                }
+                StackOperation::Over => {
+                    let a = pop(stack, token)?;
+                    let b = pop(stack, token)?;
+                    stack.push(b);
+                    stack.push(a);
+                    stack.push(b);
+                }
+                StackOperation::Rot => {
+                    let a = pop(stack, token)?;
+                    let b = pop(stack, token)?;
+                    let c = pop(stack, token)?;
+                    stack.push(b);
+                    stack.push(a);
+                    stack.push(c);
+                }
+                StackOperation::Drop => {
+                    pop(stack, token)?;
+                }
+                StackOperation::Nip => {
+                    let a = pop(stack, token)?;
+                    pop(stack, token)?;
+                    stack.push(a);
+                }
+                StackOperation::Tuck => {
+                    let a = pop(stack, token)?;
+                    let b = pop(stack, token)?;
+                    stack.extend([a, b, a]);
+                }
+                StackOperation::TwoDrop => {
+                    pop(stack, token)?;
+                    pop(stack, token)?;
+                }
+                StackOperation::Pick => {
+                    let index = pop(stack, token)?;
+                    let value = stack
+                        .peek(index)
+                        .ok_or_else(|| RuntimeError::StackUnderflow(token.to_string()))?;
+                    stack.push(value);
+                }
+                StackOperation::TwoDup => {
+                    let a = pop(stack, token)?;
+                    let b = pop(stack, token)?;
+                    stack.extend([b, a, b, a]);
+                }
+                StackOperation::PushFrame => {
+                    let len = pop(stack, token)?;
+                    stack
+                        .push_frame(len)
+                        .ok_or_else(|| RuntimeError::StackUnderflow(token.to_string()))?;
+                }
+                StackOperation::PopFrame => {
+                    stack.pop_frame().ok_or(RuntimeError::UnbalancedFrame)?;
+                }
+                StackOperation::Depth => stack.push(stack.len()),
+            },
+            Token::Putc => {
+                let value = pop(stack, token)?;
+                let char = u32::try_from(value)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or(RuntimeError::InvalidChar(value))?;
+                write!(io, "{char}")?;
+                io.flush()?;
+            }
+            Token::Putu => {
+                write!(io, "{}", pop(stack, token)?)?;
+                io.flush()?;
+            }
+            Token::Puti => {
+                write!(io, "{}", pop(stack, token)? as isize)?;
+                io.flush()?;
+            }
+            Token::Puts => {
+                let address = pop(stack, token)?;
+                let bytes = memory
+                    .get_terminated(address)
+                    .ok_or(RuntimeError::OutOfBounds(address))?;
+                io.write_all(bytes)?;
+                io.flush()?;
+            }
+            Token::Rand => stack.push(next_random(&mut self.seed) as usize),
+            Token::Seed => self.seed = pop(stack, token)? as u64,
+            Token::GetSeed => stack.push(self.seed as usize),
+            Token::Redirect => io.start_capture(),
+            Token::EndRedirect => {
+                let output = io.end_capture().ok_or(RuntimeError::UnbalancedRedirect)?;
+                stack.push(memory.extend(&output).map_err(RuntimeError::OutOfMemory)?);
+                stack.push(output.len());
+            }
+            Token::Getc => {
+                let byte = io.read_byte()?;
+                stack.push(byte.map_or(usize::MAX, usize::from));
+            }
+            Token::Getu => {
+                let mut byte = io.read_byte()?;
+                while byte.is_some_and(|byte| byte.is_ascii_whitespace()) {
+                    byte = io.read_byte()?;
+                }
+                let mut value = None;
+                while let Some(digit) = byte.filter(u8::is_ascii_digit) {
+                    let digit = (digit - b'0') as usize;
+                    value = Some(value.unwrap_or(0usize).wrapping_mul(10).wrapping_add(digit));
+                    byte = io.read_byte()?;
+                }
+                stack.push(value.unwrap_or(usize::MAX));
+            }
+            Token::Readline => {
+                let line = io.read_line()?;
+                stack.push(memory.extend(&line).map_err(RuntimeError::OutOfMemory)?);
+                stack.push(line.len());
+            }
+            Token::Getenv => {
+                let address = pop(stack, token)?;
+                let name = memory
+                    .get_terminated(address)
+                    .ok_or(RuntimeError::OutOfBounds(address))?;
+                let name = String::from_utf8_lossy(name);
+                let value = match config.env {
+                    Some(env) => env(&name),
+                    None => std::env::var(&*name).ok(),
+                };
+                match value {
+                    Some(value) => {
+                        stack.push(
+                            memory
+                                .extend(value.as_bytes())
+                                .map_err(RuntimeError::OutOfMemory)?,
+                        );
+                        stack.push(value.len());
+                        stack.push(1);
+                    }
+                    None => stack.extend([0, 0, 0]),
+                }
+            }
+            Token::Debug => match &mut config.debug {
+                Some(debug) => writeln!(debug, "{stack:?} {memory:?}")?,
+                None => writeln!(io, "{stack:?} {memory:?}")?,
+            },
+            Token::Memory(operand) => self.memory_operation(operand, token, config)?,
+            Token::IfBlock(true_block, false_block)
+            | Token::Conditional(true_block, false_block) => {
+                let segment = if pop(stack, token)? != 0 {
+                    true_block
+                } else {
+                    false_block
+                };
+                self.push_frame(segment, FrameKind::Block);
+            }
+            Token::MatchBlock(arms, default, table) => {
+                let value = pop(stack, token)?;
+                let arm = match_arm(arms, table.as_ref(), value);
+                let segment = arm.map_or(default, |arm| &arms[arm].1);
+                self.push_frame(segment, FrameKind::Block);
+            }
+            Token::LoopBlock(segment, label) => {
+                self.push_frame(segment, FrameKind::Loop(label.as_deref()))
+            }
+            Token::WhileBlock(condition, body, label) => self.push_frame(
+                condition,
+                FrameKind::While {
+                    token,
+                    label: label.as_deref(),
+                    condition,
+                    body,
+                    in_condition: true,
+                },
+            ),
+            Token::ForBlock(variable, start, end, body) => self.push_frame(
+                start,
+                FrameKind::For {
+                    token,
+                    variable,
+                    end,
+                    body,
+                    stage: ForStage::Start,
+                },
+            ),
+            Token::RepeatBlock(body) => {
+                let count = pop(stack, token)?;
+                if count > 0 {
+                    self.push_frame(
+                        body,
+                        FrameKind::For {
+                            token,
+                            variable: "",
+                            end: &[],
+                            body,
+                            stage: ForStage::Body {
+                                value: 0,
+                                next: 1,
+                                end: count,
+                            },
+                        },
+                    );
+                }
+            }
+            Token::Yield => self.yielded = true,
+            Token::Break(label) => self.break_loop(label.as_deref(), config),
+            Token::Continue(label) => self.continue_loop(label.as_deref(), config),
+            Token::Return => {
+                while let Some(frame) = self.pop_frame(config) {
+                    if let FrameKind::Call(_) = frame.kind {
+                        break;
+                    }
+                }
+            }
+            Token::FunctionCall(index) => self.call(*index, config)?,
+            Token::LetBlock(segment, let_bindings) => {
+                let mut variables = HashMap::new();
+                for let_binding in let_bindings {
+                    variables.insert(let_binding.as_str(), pop(stack, token)?);
+                }
+                self.push_frame(segment, FrameKind::Let(variables));
+            }
+            Token::Let(let_binding) => {
+                let value = self.variable(let_binding).unwrap();
+                self.stack.push(value);
+            }
+            Token::SetLet(let_binding) => {
+                let value = pop(stack, token)?;
+                self.set_variable(let_binding, value).unwrap();
+            }
+        }
+        Ok(())
+    }
+
+    fn memory_operation(
+        &mut self,
+        operand: &MemoryOperation,
+        token: &Token,
+        config: &Config,
+    ) -> Result<(), RuntimeError> {
+        let stack = &mut self.stack;
+        let memory = &mut self.memory;
+        match operand {
+            MemoryOperation::PushBytes(data) => {
+                let address = memory.extend(data).map_err(RuntimeError::OutOfMemory)?;
+                if config.read_only_literals {
+                    memory.protect(address, data.len());
+                }
+                stack.push(address);
+            }
+            MemoryOperation::LoadByte => {
+                let address = pop(stack, token)?;
+                let value = memory
+                    .get(address)
+                    .ok_or(RuntimeError::OutOfBounds(address))?;
+                stack.push(*value as usize);
+            }
+            MemoryOperation::StoreByte => {
+                let value = pop(stack, token)?;
+                let address = pop(stack, token)?;
+                writable(memory, address, 1)?;
+                memory
+                    .set(address, value as u8)
+                    .ok_or(RuntimeError::OutOfBounds(address))?;
+            }
+            MemoryOperation::LoadWord => {
+                let address = pop(stack, token)?;
+                let value = memory
+                    .get_word(address)
+                    .ok_or(RuntimeError::OutOfBounds(address))?;
+                stack.push(value);
+            }
+            MemoryOperation::StoreWord => {
+                let value = pop(stack, token)?;
+                let address = pop(stack, token)?;
+                writable(memory, address, WORD_SIZE)?;
+                memory
+                    .set_word(address, value)
+                    .ok_or(RuntimeError::OutOfBounds(address))?;
+            }
+            MemoryOperation::Free => {
+                let len = pop(stack, token)?;
+                let address = pop(stack, token)?;
+                memory.remove(address, len)?;
+            }
+            MemoryOperation::Alloc => {
+                let len = pop(stack, token)?;
+                let address = memory.alloc(len).map_err(RuntimeError::OutOfMemory)?;
+                stack.push(address);
+            }
+            MemoryOperation::Here => stack.push(memory.here()),
+            MemoryOperation::ByteStackInit => {
+                let capacity = pop(stack, token)?;
+                let base = pop(stack, token)?;
+                self.byte_stack = ByteStack {
+                    base,
+                    capacity,
+                    len: 0,
+                };
+            }
+            MemoryOperation::ByteStackPush => {
+                let value = pop(stack, token)?;
+                let byte_stack = &mut self.byte_stack;
+                if byte_stack.len == byte_stack.capacity {
+                    return Err(RuntimeError::ByteStackOverflow(byte_stack.capacity));
+                }
+                let address = byte_stack.base + byte_stack.len;
+                writable(memory, address, 1)?;
+                memory
+                    .set(address, value as u8)
+                    .ok_or(RuntimeError::OutOfBounds(address))?;
+                byte_stack.len += 1;
+            }
+            MemoryOperation::ByteStackPop => {
+                let byte_stack = &mut self.byte_stack;
+                if byte_stack.len == 0 {
+                    return Err(RuntimeError::ByteStackUnderflow);
+                }
+                let address = byte_stack.base + byte_stack.len - 1;
+                let value = memory
+                    .get(address)
+                    .ok_or(RuntimeError::OutOfBounds(address))?;
+                stack.push(*value as usize);
+                byte_stack.len -= 1;
+            }
+            MemoryOperation::CountedAlloc => {
+                let len = pop(stack, token)?;
+                stack.push(
+                    memory
+                        .alloc_counted(len)
+                        .map_err(RuntimeError::OutOfMemory)?,
+                );
+            }
+            MemoryOperation::CountedAddress => {
+                let handle = pop(stack, token)?;
+                let address = memory
+                    .counted_address(handle)
+                    .ok_or(RuntimeError::InvalidHandle(handle))?;
+                stack.push(address);
+            }
+            MemoryOperation::IncrementReferences => {
+                let handle = pop(stack, token)?;
+                memory
+                    .increment_references(handle)
+                    .ok_or(RuntimeError::InvalidHandle(handle))?;
+            }
+            MemoryOperation::DecrementReferences => {
+                let handle = pop(stack, token)?;
+                memory
+                    .decrement_references(handle)
+                    .ok_or(RuntimeError::InvalidHandle(handle))?;
+            }
+            MemoryOperation::MapNew => {
+                stack.push(memory.map_new().map_err(RuntimeError::OutOfMemory)?);
+            }
+            MemoryOperation::MapSet => {
+                let key = pop(stack, token)?;
+                let value = pop(stack, token)?;
+                let table = pop(stack, token)?;
+                memory.map_set(table, key, value)?;
+            }
+            MemoryOperation::MapGet => {
+                let key = pop(stack, token)?;
+                let table = pop(stack, token)?;
+                let value = memory.map_get(table, key)?;
+                stack.push(value.unwrap_or(0));
+                stack.push(value.is_some() as usize);
+            }
+            MemoryOperation::Copy => {
+                let len = pop(stack, token)?;
+                let source = pop(stack, token)?;
+                let destination = pop(stack, token)?;
+                writable(memory, destination, len)?;
+                memory
+                    .copy_within(destination, source, len)
+                    .map_err(RuntimeError::OutOfBounds)?;
+            }
+            MemoryOperation::Fill => {
+                let len = pop(stack, token)?;
+                let value = pop(stack, token)?;
+                let address = pop(stack, token)?;
+                writable(memory, address, len)?;
+                memory
+                    .fill(address, value as u8, len)
+                    .map_err(RuntimeError::OutOfBounds)?;
+            }
+            MemoryOperation::Increment | MemoryOperation::Decrement => {
+                let address = pop(stack, token)?;
+                let value = memory
+                    .get_word(address)
+                    .ok_or(RuntimeError::OutOfBounds(address))?;
+                let value = match operand {
+                    MemoryOperation::Increment => value.wrapping_add(1),
+                    _ => value.wrapping_sub(1),
+                };
+                writable(memory, address, WORD_SIZE)?;
+                memory.set_word(address, value);
+                stack.push(value);
+            }
+            MemoryOperation::EditDistance => {
+                let b_len = pop(stack, token)?;
+                let b_address = pop(stack, token)?;
+                let a_len = pop(stack, token)?;
+                let a_address = pop(stack, token)?;
+                let limit = config.edit_distance_limit.unwrap_or(EDIT_DISTANCE_LIMIT);
+                let cells = a_len.saturating_mul(b_len);
+                if cells > limit {
+                    return Err(RuntimeError::EditDistanceLimit { cells, limit });
+                }
+                let a = memory
+                    .get_range(a_address, a_len)
+                    .ok_or(RuntimeError::OutOfBounds(a_address))?;
+                let b = memory
+                    .get_range(b_address, b_len)
+                    .ok_or(RuntimeError::OutOfBounds(b_address))?;
+                stack.push(edit_distance(a, b));
+            }
+            MemoryOperation::Histogram => {
+                let destination = pop(stack, token)?;
+                let len = pop(stack, token)?;
+                let address = pop(stack, token)?;
+                let mut counts = vec![0; 256];
+                for byte in memory
+                    .get_range(address, len)
+                    .ok_or(RuntimeError::OutOfBounds(address))?
+                {
+                    counts[*byte as usize] += 1;
+                }
+                memory
+                    .get_range(destination, counts.len() * WORD_SIZE)
+                    .ok_or(RuntimeError::OutOfBounds(destination))?;
+                writable(memory, destination, counts.len() * WORD_SIZE)?;
+                for (i, count) in counts.into_iter().enumerate() {
+                    memory.set_word(destination + i * WORD_SIZE, count);
+                }
+            }
+            MemoryOperation::Crc32 => {
+                let len = pop(stack, token)?;
+                let address = pop(stack, token)?;
+                let bytes = memory
+                    .get_range(address, len)
+                    .ok_or(RuntimeError::OutOfBounds(address))?;
+                stack.push(crc32(bytes) as usize);
+            }
+            MemoryOperation::FormatBase => {
+                let base = pop(stack, token)?;
+                let value = pop(stack, token)?;
+                let digits = format_base(value, base).ok_or(RuntimeError::InvalidBase(base))?;
+                stack.push(memory.extend(&digits).map_err(RuntimeError::OutOfMemory)?);
+                stack.push(digits.len());
+            }
+            MemoryOperation::CrlfToLf => {
+                let len = pop(stack, token)?;
+                let address = pop(stack, token)?;
+                writable(memory, address, len)?;
+                let bytes = memory
+                    .get_range_mut(address, len)
+                    .ok_or(RuntimeError::OutOfBounds(address))?;
+                let mut new_len = 0;
+                for i in 0..bytes.len() {
+                    if bytes[i] == b'\r' && bytes.get(i + 1) == Some(&b'\n') {
+                        continue;
+                    }
+                    bytes[new_len] = bytes[i];
+                    new_len += 1;
+                }
+                stack.push(new_len);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FrameKind<'_> {
+    // any loop when no label is given, otherwise only the loop with that label
+    fn is_loop(&self, label: Option<&str>) -> bool {
+        match self {
+            FrameKind::Loop(own) | FrameKind::While { label: own, .. } => {
+                label.is_none() || label == *own
+            }
+            FrameKind::For { .. } => label.is_none(),
+            _ => false,
+        }
+    }
+}
+
+fn clock_seed() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos() as u64)
+}
+
+// index of the arm with the value as its label
+pub(super) fn match_arm(
+    arms: &[(usize, Vec<Token>)],
+    table: Option<&JumpTable>,
+    value: usize,
+) -> Option<usize> {
+    match table {
+        Some(table) => value
+            .checked_sub(table.start)
+            .and_then(|index| table.arms.get(index).copied().flatten()),
+        None => arms.iter().position(|(label, _)| *label == value),
+    }
+}
+
+// pops the top of the stack, naming the token that needed it on underflow
+pub(super) fn pop(stack: &mut Stack, token: &Token) -> Result<usize, RuntimeError> {
+    stack
+        .pop()
+        .ok_or_else(|| RuntimeError::StackUnderflow(token.to_string()))
+}
+
+// fails when the range overlaps a string literal that can't be written to
+fn writable(memory: &Memory, address: usize, len: usize) -> Result<(), RuntimeError> {
+    match memory.is_writable(address, len) {
+        true => Ok(()),
+        false => Err(RuntimeError::WriteToReadOnly(address)),
+    }
+}
@@ -1,69 +1,365 @@
 use std::fmt::Debug;
 
 static FREE_MEMORY: usize = 2_usize.pow(32);
-
+pub const WORD_SIZE: usize = std::mem::size_of::<usize>();
 
 #[derive(Debug)]
 pub struct Memory {
     memory: Vec<u8>,
     free: Vec<(usize, usize)>,
+    counted: Vec<Option<Counted>>, // indexed by handle, None once the region is freed
+    allocations: Vec<(usize, usize)>, // addresses and lengths of the allocations with bytes that are not freed yet
+    limit: usize,                     // size of the address space, nothing is allocated past it
+    read_only: Vec<(usize, usize)>, // addresses and lengths of the regions that can't be written to until they are freed
+}
+
+// a map is a header of three words: the address of its entries, their capacity and how many are used,
+// every entry is three words: whether it is used, the key and the value
+const MAP_HEADER: usize = 3 * WORD_SIZE;
+const MAP_ENTRY: usize = 3 * WORD_SIZE;
+const MAP_CAPACITY: usize = 8; // entries of a new map, doubled whenever three quarters of them are used
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MapError {
+    InvalidMap(usize), // the address of a header that can't belong to a map
+    OutOfBounds(usize),
+    OutOfMemory(usize),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FreeError {
+    OutOfBounds(usize),
+    NotAllocated(usize), // the address of a range that is at least partly free already
+}
+
+// a region that is freed as soon as nothing refers to it
+#[derive(Debug)]
+struct Counted {
+    address: usize,
+    len: usize,
+    references: usize,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Memory {
     pub fn new() -> Self {
+        Self::with_limit(FREE_MEMORY)
+    }
+
+    // at most `limit` bytes can be allocated at once
+    pub fn with_limit(limit: usize) -> Self {
         Self {
             memory: Vec::new(),
-            free: vec![(0, FREE_MEMORY)],
+            free: vec![(0, limit)],
+            counted: Vec::new(),
+            allocations: Vec::new(),
+            limit,
+            read_only: Vec::new(),
         }
     }
-    pub fn extend(&mut self, data: &[u8]) -> usize {
-        let index = self
-            .free
-            .iter()
-            .position(|&(_address, free)| free >= data.len())
-            .unwrap();
-        let (address, remaining) = self.free.get_mut(index).unwrap();
-        let starting_address = *address;
-        self.memory
-            .resize(self.memory.len().max(*address + data.len()), 0); // extend memory;
-        *remaining -= data.len();
 
-        for value in data {
-            self.memory[*address] = *value;
-            *address += 1;
-        }
-        if *remaining == 0 {
-            self.free.remove(0);
-        }
-        starting_address
+    // allocates a copy of the data, failing like `alloc`
+    pub fn extend(&mut self, data: &[u8]) -> Result<usize, usize> {
+        let address = self.alloc(data.len())?;
+        self.memory[address..address + data.len()].copy_from_slice(data);
+        Ok(address)
     }
-    pub fn alloc(&mut self, len: usize) -> usize {
+
+    // on failure returns the length, which neither a free block nor the host can provide
+    pub fn alloc(&mut self, len: usize) -> Result<usize, usize> {
         let index = self
             .free
             .iter()
             .position(|&(_address, free)| free >= len)
-            .unwrap();
+            .ok_or(len)?;
+        let end = self.free[index].0 + len;
+        if let Some(missing) = end.checked_sub(self.memory.len()) {
+            self.memory.try_reserve(missing).map_err(|_| len)?;
+        }
         let (address, remaining) = self.free.get_mut(index).unwrap();
         let starting_address = *address;
-        self.memory.resize(self.memory.len().max(*address + len), 0); // extend memory;
+        self.memory.resize(self.memory.len().max(end), 0); // extend memory;
+        *address += len;
         *remaining -= len;
 
         if *remaining == 0 {
-            self.free.remove(0);
+            self.free.remove(index);
         }
-        starting_address
+        self.allocations.push((starting_address, len));
+        Ok(starting_address)
+    }
+    // number of bytes that are currently allocated
+    pub fn allocated(&self) -> usize {
+        let free = self.free.iter().map(|(_, len)| len).sum::<usize>();
+        self.limit.saturating_sub(free)
     }
+
+    // number of allocations with bytes that are not freed yet, freeing a part of one doesn't release it
+    pub fn allocations(&self) -> usize {
+        self.allocations.len()
+    }
+
+    // the start of the first free block, where the next allocation that fits it is placed
+    pub fn here(&self) -> usize {
+        self.free
+            .first()
+            .map_or(self.memory.len(), |&(address, _)| address)
+    }
+
     #[inline]
     pub fn get(&self, index: usize) -> Option<&u8> {
         self.memory.get(index)
     }
 
     #[inline]
-    pub fn set(&mut self, index: usize, value: u8) {
-        *self.memory.get_mut(index).unwrap() = value;
+    pub fn set(&mut self, index: usize, value: u8) -> Option<()> {
+        *self.memory.get_mut(index)? = value;
+        Some(())
+    }
+
+    #[inline]
+    pub fn get_range(&self, index: usize, len: usize) -> Option<&[u8]> {
+        self.memory.get(index..index.checked_add(len)?)
+    }
+
+    #[inline]
+    pub fn get_range_mut(&mut self, index: usize, len: usize) -> Option<&mut [u8]> {
+        self.memory.get_mut(index..index.checked_add(len)?)
+    }
+
+    // on failure returns the start of the range that is out of bounds
+    pub fn copy_within(
+        &mut self,
+        destination: usize,
+        source: usize,
+        len: usize,
+    ) -> Result<(), usize> {
+        self.get_range(source, len).ok_or(source)?;
+        self.get_range(destination, len).ok_or(destination)?;
+        self.memory.copy_within(source..source + len, destination);
+        Ok(())
+    }
+
+    // the bytes from the address up to the terminating zero, like the ones of string literals
+    pub fn get_terminated(&self, index: usize) -> Option<&[u8]> {
+        let bytes = self.memory.get(index..)?;
+        let len = bytes.iter().position(|&byte| byte == 0)?;
+        Some(&bytes[..len])
+    }
+
+    // on failure returns the start of the range, as nothing is allocated implicitly
+    pub fn fill(&mut self, address: usize, value: u8, len: usize) -> Result<(), usize> {
+        self.get_range_mut(address, len).ok_or(address)?.fill(value);
+        Ok(())
+    }
+
+    // the region can't be written to until it is freed, see `is_writable`
+    pub fn protect(&mut self, address: usize, len: usize) {
+        self.read_only.push((address, len));
+    }
+
+    pub fn is_writable(&self, address: usize, len: usize) -> bool {
+        let end = address.saturating_add(len);
+        self.read_only
+            .iter()
+            .all(|&(start, size)| end <= start || start + size <= address)
+    }
+
+    // words are stored little-endian
+    pub fn get_word(&self, index: usize) -> Option<usize> {
+        let bytes = self.memory.get(index..index.checked_add(WORD_SIZE)?)?;
+        Some(usize::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn set_word(&mut self, index: usize, value: usize) -> Option<()> {
+        let bytes = self.memory.get_mut(index..index.checked_add(WORD_SIZE)?)?;
+        bytes.copy_from_slice(&value.to_le_bytes());
+        Some(())
+    }
+
+    // allocates a region with a single reference, returning its handle
+    pub fn alloc_counted(&mut self, len: usize) -> Result<usize, usize> {
+        let address = self.alloc(len)?;
+        self.counted.push(Some(Counted {
+            address,
+            len,
+            references: 1,
+        }));
+        Ok(self.counted.len() - 1)
+    }
+
+    // the address of the region, None when the handle was never returned or its region is freed
+    pub fn counted_address(&self, handle: usize) -> Option<usize> {
+        let counted = self.counted.get(handle)?.as_ref()?;
+        Some(counted.address)
     }
 
-    pub fn remove(&mut self, address: usize, len: usize) {
+    pub fn increment_references(&mut self, handle: usize) -> Option<()> {
+        let counted = self.counted.get_mut(handle)?.as_mut()?;
+        counted.references += 1;
+        Some(())
+    }
+
+    // frees the region once the last reference is dropped, handles are never reused
+    pub fn decrement_references(&mut self, handle: usize) -> Option<()> {
+        let slot = self.counted.get_mut(handle)?;
+        let counted = slot.as_mut()?;
+        counted.references -= 1;
+        if counted.references == 0 {
+            let Counted { address, len, .. } = slot.take()?;
+            self.remove(address, len).ok()?;
+        }
+        Some(())
+    }
+
+    // allocates an empty map, failing like `alloc`
+    pub fn map_new(&mut self) -> Result<usize, usize> {
+        let entries = self.alloc(MAP_CAPACITY * MAP_ENTRY)?;
+        let table = self.alloc(MAP_HEADER).inspect_err(|_| {
+            // the entries were just allocated, so freeing them can't fail
+            let _ = self.remove(entries, MAP_CAPACITY * MAP_ENTRY);
+        })?;
+        self.set_word(table, entries);
+        self.set_word(table + WORD_SIZE, MAP_CAPACITY);
+        Ok(table)
+    }
+
+    // replaces the value when the key is already in the map
+    pub fn map_set(&mut self, table: usize, key: usize, value: usize) -> Result<(), MapError> {
+        let (mut entries, mut capacity, len) = self.map_header(table)?;
+        let (mut entry, found) = self.map_probe(table, entries, capacity, key)?;
+        if !found && len + 1 > capacity / 4 * 3 {
+            (entries, capacity) = self.map_grow(table, entries, capacity)?;
+            entry = self.map_probe(table, entries, capacity, key)?.0;
+        }
+        if !found {
+            self.set_word(entry, 1);
+            self.set_word(entry + WORD_SIZE, key);
+            self.set_word(table + 2 * WORD_SIZE, len + 1);
+        }
+        self.set_word(entry + 2 * WORD_SIZE, value);
+        Ok(())
+    }
+
+    // None when the key is not in the map
+    pub fn map_get(&self, table: usize, key: usize) -> Result<Option<usize>, MapError> {
+        let (entries, capacity, _) = self.map_header(table)?;
+        match self.map_probe(table, entries, capacity, key)? {
+            (entry, true) => Ok(self.get_word(entry + 2 * WORD_SIZE)),
+            (_, false) => Ok(None),
+        }
+    }
+
+    fn map_header(&self, table: usize) -> Result<(usize, usize, usize), MapError> {
+        let word = |index: usize| self.get_word(table.checked_add(index * WORD_SIZE)?);
+        match (word(0), word(1), word(2)) {
+            (Some(entries), Some(capacity), Some(len))
+                if capacity.is_power_of_two() && len < capacity =>
+            {
+                Ok((entries, capacity, len))
+            }
+            _ => Err(MapError::InvalidMap(table)),
+        }
+    }
+
+    // linear probing from the hash of the key, returning the entry holding it or,
+    // when it is missing, the empty entry it would be stored in
+    fn map_probe(
+        &self,
+        table: usize,
+        entries: usize,
+        capacity: usize,
+        key: usize,
+    ) -> Result<(usize, bool), MapError> {
+        let hash = (key as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32;
+        let mut index = hash as usize & (capacity - 1);
+        for _ in 0..capacity {
+            let entry = index
+                .checked_mul(MAP_ENTRY)
+                .and_then(|offset| entries.checked_add(offset))
+                .filter(|&entry| self.get_range(entry, MAP_ENTRY).is_some())
+                .ok_or(MapError::OutOfBounds(entries))?;
+            if self.get_word(entry) == Some(0) {
+                return Ok((entry, false));
+            }
+            if self.get_word(entry + WORD_SIZE) == Some(key) {
+                return Ok((entry, true));
+            }
+            index = (index + 1) & (capacity - 1);
+        }
+        // the header says some entries are empty, so the map was overwritten
+        Err(MapError::InvalidMap(table))
+    }
+
+    // moves the entries into a region twice as large, returning its address and capacity
+    fn map_grow(
+        &mut self,
+        table: usize,
+        entries: usize,
+        capacity: usize,
+    ) -> Result<(usize, usize), MapError> {
+        let old = self
+            .get_range(entries, capacity * MAP_ENTRY)
+            .ok_or(MapError::OutOfBounds(entries))?;
+        let pairs: Vec<(usize, usize)> = old
+            .chunks_exact(MAP_ENTRY)
+            .map(|entry| {
+                let word = |index: usize| {
+                    let bytes = &entry[index * WORD_SIZE..(index + 1) * WORD_SIZE];
+                    usize::from_le_bytes(bytes.try_into().unwrap())
+                };
+                (word(0), word(1), word(2))
+            })
+            .filter(|&(used, _, _)| used != 0)
+            .map(|(_, key, value)| (key, value))
+            .collect();
+
+        let new_capacity = capacity * 2;
+        let new_entries = self
+            .alloc(new_capacity * MAP_ENTRY)
+            .map_err(MapError::OutOfMemory)?;
+        for (key, value) in pairs {
+            let (entry, _) = self.map_probe(table, new_entries, new_capacity, key)?;
+            self.set_word(entry, 1);
+            self.set_word(entry + WORD_SIZE, key);
+            self.set_word(entry + 2 * WORD_SIZE, value);
+        }
+        // the header may have been overwritten with entries that are not allocated
+        self.remove(entries, capacity * MAP_ENTRY)
+            .map_err(|_| MapError::InvalidMap(table))?;
+        self.set_word(table, new_entries);
+        self.set_word(table + WORD_SIZE, new_capacity);
+        Ok((new_entries, new_capacity))
+    }
+
+    // fails without freeing anything when the range is past the end of memory or any of it is free already
+    pub fn remove(&mut self, address: usize, len: usize) -> Result<(), FreeError> {
+        let end = address
+            .checked_add(len)
+            .filter(|&end| end <= self.memory.len())
+            .ok_or(FreeError::OutOfBounds(address))?;
+        if len > 0
+            && self
+                .free
+                .iter()
+                .any(|&(start, size)| start < end && address < start + size)
+        {
+            return Err(FreeError::NotAllocated(address));
+        }
+        if len == 0 {
+            // only releases an empty allocation at the address
+            if let Some(index) = self.allocations.iter().position(|&a| a == (address, 0)) {
+                self.allocations.swap_remove(index);
+            }
+            return Ok(());
+        }
+        self.read_only
+            .retain(|&(start, size)| end <= start || start + size <= address);
         // NOTE: maybe there is no need to reset the memory to zeros
         for i in 0..len {
             self.memory[address + i] = 0;
@@ -81,6 +377,13 @@ impl Memory {
             }
         }
         self.free = new_free;
+        let free = &self.free;
+        self.allocations.retain(|&(start, size)| {
+            !free
+                .iter()
+                .any(|&(block, block_size)| block <= start && start + size <= block + block_size)
+        });
+        Ok(())
     }
 }
 
@@ -89,44 +392,155 @@ fn test_memory() {
     let mut memory = Memory::new();
     assert_eq!(memory.memory, vec![]);
     assert_eq!(memory.free, vec![(0, FREE_MEMORY)]);
-    let address = memory.extend(&[1, 1, 1, 1]);
+    let address = memory.extend(&[1, 1, 1, 1]).unwrap();
     assert_eq!(address, 0);
-    let address = memory.extend(&[2, 2, 2]);
+    let address = memory.extend(&[2, 2, 2]).unwrap();
     assert_eq!(address, 4);
-    let address = memory.extend(&[3]);
+    let address = memory.extend(&[3]).unwrap();
     assert_eq!(address, 7);
     assert_eq!(memory.memory, vec![1, 1, 1, 1, 2, 2, 2, 3]);
     assert_eq!(memory.free, vec![(8, FREE_MEMORY - 8)]);
-    memory.remove(1, 4);
+    memory.remove(1, 4).unwrap();
     assert_eq!(memory.memory, vec![1, 0, 0, 0, 0, 2, 2, 3]);
     assert_eq!(memory.free, vec![(1, 4), (8, FREE_MEMORY - 8)]);
-    let address = memory.extend(&[4]);
+    let address = memory.extend(&[4]).unwrap();
     assert_eq!(address, 1);
     assert_eq!(memory.memory, vec![1, 4, 0, 0, 0, 2, 2, 3]);
     assert_eq!(memory.free, vec![(2, 3), (8, FREE_MEMORY - 8)]);
-    let address = memory.extend(&[5]);
+    let address = memory.extend(&[5]).unwrap();
     assert_eq!(address, 2);
     assert_eq!(memory.memory, vec![1, 4, 5, 0, 0, 2, 2, 3]);
     assert_eq!(memory.free, vec![(3, 2), (8, FREE_MEMORY - 8)]);
-    let address = memory.extend(&[6, 6, 6]);
+    let address = memory.extend(&[6, 6, 6]).unwrap();
     assert_eq!(address, 8);
     assert_eq!(memory.memory, vec![1, 4, 5, 0, 0, 2, 2, 3, 6, 6, 6]);
     assert_eq!(memory.free, vec![(3, 2), (11, FREE_MEMORY - 11)]);
-    let address = memory.extend(&[7, 7]);
+    let address = memory.extend(&[7, 7]).unwrap();
     assert_eq!(address, 3);
     assert_eq!(memory.memory, vec![1, 4, 5, 7, 7, 2, 2, 3, 6, 6, 6]);
     assert_eq!(memory.free, vec![(11, FREE_MEMORY - 11)]);
-    memory.remove(4, 1);
-    let address = memory.extend(&[8]);
+    memory.remove(4, 1).unwrap();
+    let address = memory.extend(&[8]).unwrap();
     assert_eq!(address, 4);
     assert_eq!(memory.memory, vec![1, 4, 5, 7, 8, 2, 2, 3, 6, 6, 6]);
     assert_eq!(memory.free, vec![(11, FREE_MEMORY - 11)]);
-    memory.remove(0, memory.memory.len());
+    memory.remove(0, memory.memory.len()).unwrap();
     assert_eq!(memory.memory, vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
     assert_eq!(memory.free, vec![(0, FREE_MEMORY)]);
 
     let mut memory = Memory::new();
-    memory.alloc(5);
+    assert_eq!(memory.alloc(5), Ok(0));
     assert_eq!(memory.memory, vec![0, 0, 0, 0, 0]);
-    assert_eq!(memory.free, vec![(0, FREE_MEMORY - 5)]);
+    assert_eq!(memory.free, vec![(5, FREE_MEMORY - 5)]);
+    assert_eq!(memory.here(), 5);
+    assert_eq!(memory.alloc(2), Ok(5));
+}
+
+#[test]
+fn test_memory_limit() {
+    let mut memory = Memory::with_limit(8);
+    assert_eq!(memory.alloc(5), Ok(0));
+    assert_eq!(memory.extend(&[1, 2, 3, 4]), Err(4));
+    assert_eq!(memory.alloc_counted(4), Err(4));
+    assert_eq!(memory.extend(&[1, 2, 3]), Ok(5));
+    assert_eq!(memory.allocated(), 8);
+    memory.remove(0, 5).unwrap();
+    assert_eq!(memory.alloc(5), Ok(0));
+    assert_eq!(memory.alloc(1), Err(1));
+
+    // more than the host can provide fails like going past the limit, instead of aborting
+    let mut memory = Memory::with_limit(usize::MAX);
+    assert_eq!(memory.alloc(usize::MAX - 1), Err(usize::MAX - 1));
+    assert_eq!(memory.allocated(), 0);
+    assert_eq!(memory.alloc(3), Ok(0));
+}
+
+#[test]
+fn test_fragmented_memory() {
+    let mut memory = Memory::with_limit(12);
+    for address in [0, 3, 6, 9] {
+        assert_eq!(memory.alloc(3), Ok(address));
+    }
+    memory.remove(0, 3).unwrap();
+    memory.remove(6, 3).unwrap();
+    assert_eq!(memory.allocated(), 6);
+    assert_eq!(memory.alloc(4), Err(4));
+    assert_eq!(memory.alloc(3), Ok(0));
+    memory.remove(3, 3).unwrap();
+    assert_eq!(memory.alloc(6), Ok(3));
+}
+
+#[test]
+fn test_allocations() {
+    let mut memory = Memory::new();
+    for _ in 0..3 {
+        memory.alloc(4).unwrap();
+    }
+    assert_eq!(memory.allocations(), 3);
+    // freeing nothing or only a part of an allocation doesn't release it
+    for _ in 0..10 {
+        memory.remove(4, 0).unwrap();
+    }
+    memory.remove(4, 2).unwrap();
+    assert_eq!(memory.allocations(), 3);
+    // the rest of it releases it, together with the whole allocation freed by the same range
+    memory.remove(6, 6).unwrap();
+    assert_eq!(memory.allocations(), 1);
+    let address = memory.alloc(0).unwrap();
+    assert_eq!(memory.allocations(), 2);
+    memory.remove(address, 0).unwrap();
+    assert_eq!(memory.allocations(), 1);
+}
+
+#[test]
+fn test_invalid_free() {
+    let mut memory = Memory::with_limit(16);
+    assert_eq!(memory.alloc(8), Ok(0));
+    assert_eq!(memory.remove(4, 8), Err(FreeError::OutOfBounds(4)));
+    assert_eq!(
+        memory.remove(usize::MAX, 2),
+        Err(FreeError::OutOfBounds(usize::MAX))
+    );
+    memory.remove(2, 2).unwrap();
+    assert_eq!(memory.remove(0, 4), Err(FreeError::NotAllocated(0)));
+    // nothing is freed by the ranges that are rejected
+    assert_eq!(memory.free, vec![(2, 2), (8, 8)]);
+    assert_eq!(memory.allocated(), 6);
+}
+
+#[test]
+fn test_map() {
+    let mut memory = Memory::new();
+    let table = memory.map_new().unwrap();
+    for key in 0..100 {
+        memory.map_set(table, key * 7, key).unwrap();
+    }
+    memory.map_set(table, 14, 1000).unwrap();
+    for key in 0..100 {
+        let value = if key == 2 { 1000 } else { key };
+        assert_eq!(memory.map_get(table, key * 7), Ok(Some(value)));
+    }
+    assert_eq!(memory.map_get(table, 8), Ok(None));
+    assert_eq!(memory.map_get(table, usize::MAX), Ok(None));
+    // growing frees the old entries, leaving only the header and the current ones
+    assert_eq!(memory.allocations(), 2);
+    assert_eq!(
+        memory.map_get(table + 1, 0),
+        Err(MapError::InvalidMap(table + 1))
+    );
+    assert_eq!(
+        memory.map_get(usize::MAX, 0),
+        Err(MapError::InvalidMap(usize::MAX))
+    );
+
+    let mut memory = Memory::with_limit(MAP_CAPACITY * MAP_ENTRY + MAP_HEADER);
+    let table = memory.map_new().unwrap();
+    for key in 0..6 {
+        memory.map_set(table, key, key).unwrap();
+    }
+    assert_eq!(
+        memory.map_set(table, 6, 6),
+        Err(MapError::OutOfMemory(2 * MAP_CAPACITY * MAP_ENTRY))
+    );
+    assert_eq!(memory.map_get(table, 5), Ok(Some(5)));
 }
@@ -1,71 +1,609 @@
 use crate::io::Io;
 use crate::token::*;
-use std::{collections::HashMap, io::Write, iter::Peekable};
+use derive_more::Display;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet, VecDeque},
+    io::{Read, Write},
+    num::IntErrorKind,
+    path::{Path, PathBuf},
+};
 
+mod bytecode;
+mod codegen;
+mod effect;
+mod functions;
+mod machine;
 mod memory;
-use memory::Memory;
+mod repl;
+mod stack;
+mod stats;
+pub use bytecode::CompiledProgram;
+use functions::Functions;
+pub use machine::{Machine, StepResult};
+pub use memory::Memory;
+pub use repl::{Repl, ReplError};
+use stack::Stack;
+pub use stats::{FunctionStats, RunStats};
+
+// most nested function calls allowed when the config sets no limit
+pub const RECURSION_LIMIT: usize = 10000;
+
+// most steps and bytes of memory the evaluation of a pure call at parse time may use
+const FOLD_LIMIT: usize = 10000;
+
+// largest product of lengths `editdist` accepts when the config sets no limit
+pub const EDIT_DISTANCE_LIMIT: usize = 1 << 24;
+
+// location of a token in the source, lines and columns start at 1
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+#[display(fmt = "line {}, col {}", line, column)]
+pub struct Span {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Display, PartialEq, Eq)]
+#[display(fmt = "at {}: {}", span, kind)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Display, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    #[display(fmt = "unexpected token `{}`, expected `{}`", found, expected)]
+    UnexpectedToken {
+        found: String,
+        expected: &'static str,
+    },
+    #[display(fmt = "unexpected end of file")]
+    UnexpectedEof,
+    #[display(fmt = "block comment is never closed")]
+    UnterminatedComment,
+    #[display(fmt = "unknown token `{}`", _0)]
+    UnknownToken(String),
+    #[display(fmt = "call to undefined function `{}`", _0)]
+    UndefinedFunction(String),
+    #[display(fmt = "no `main` function is defined")]
+    MissingMain,
+    #[display(fmt = "invalid literal `{}`", _0)]
+    InvalidLiteral(String),
+    #[display(fmt = "integer literal `{}` does not fit into a word", _0)]
+    IntegerLiteralOverflow(String),
+    #[display(fmt = "case `{}` is already handled", _0)]
+    DuplicateCase(String),
+    #[display(fmt = "pure function `{}` uses `{}`", function, token)]
+    ImpureFunction { function: String, token: String },
+    #[display(
+        fmt = "function `{}` declares {} results, but leaves {} values",
+        function,
+        expected,
+        found
+    )]
+    ResultCount {
+        function: String,
+        expected: usize,
+        found: isize,
+    },
+    #[display(fmt = "stack effect of function `{}` can't be determined", _0)]
+    UnknownStackEffect(String),
+    #[display(fmt = "both blocks of `?` must leave a single value")]
+    ConditionalValue,
+    #[display(fmt = "function `{}` is already defined", _0)]
+    DuplicateFunction(String),
+    #[display(fmt = "global `{}` is already defined", _0)]
+    DuplicateGlobal(String),
+    #[display(fmt = "constant `{}` is already defined", _0)]
+    DuplicateConstant(String),
+    #[display(fmt = "can't read file `{}`", _0)]
+    IncludeFailed(String),
+    #[display(fmt = "file `{}` includes itself", _0)]
+    CircularInclude(String),
+    #[display(fmt = "in `{}` {}", path, error)]
+    Included {
+        path: String,
+        error: Box<ParseError>,
+    },
+}
+
+#[derive(Debug, Display, PartialEq, Eq)]
+pub enum MergeError {
+    #[display(fmt = "function `{}` is defined by both programs", _0)]
+    DuplicateFunction(String),
+    // the addresses of globals are part of the code using them, so they can't be moved
+    #[display(fmt = "both programs define globals, whose addresses would overlap")]
+    OverlappingGlobals,
+}
+
+#[derive(Debug, Display, PartialEq, Eq)]
+pub enum RuntimeError {
+    #[display(fmt = "no entry point function `{}` provided", _0)]
+    MissingEntryPoint(String),
+    #[display(fmt = "unknown function `{}`", _0)]
+    UnknownFunction(String),
+    #[display(fmt = "stack underflow in `{}`", _0)]
+    StackUnderflow(String),
+    #[display(fmt = "shift amount {} exceeds the word width", _0)]
+    ShiftOverflow(usize),
+    #[display(fmt = "memory access out of bounds at address {}", _0)]
+    OutOfBounds(usize),
+    #[display(fmt = "base {} is not between 2 and 36", _0)]
+    InvalidBase(usize),
+    #[display(fmt = "division by zero")]
+    DivByZero,
+    #[display(fmt = "{} is not a valid character", _0)]
+    InvalidChar(usize),
+    #[display(fmt = "io error: {}", _0)]
+    Io(std::io::ErrorKind),
+    #[display(fmt = "`endredirect` without a matching `redirect`")]
+    UnbalancedRedirect,
+    #[display(
+        fmt = "edit distance of {} cells exceeds the limit of {}",
+        cells,
+        limit
+    )]
+    EditDistanceLimit { cells: usize, limit: usize },
+    #[display(fmt = "`popframe` without a matching `pushframe`")]
+    UnbalancedFrame,
+    #[display(fmt = "{} is not the handle of a live region", _0)]
+    InvalidHandle(usize),
+    #[display(fmt = "more than {} allocations are not freed", _0)]
+    AllocationLimit(usize),
+    #[display(fmt = "byte stack of {} bytes is full", _0)]
+    ByteStackOverflow(usize),
+    #[display(fmt = "{} is not the address of a map", _0)]
+    InvalidMap(usize),
+    #[display(fmt = "memory at address {} is not allocated, it can't be freed", _0)]
+    InvalidFree(usize),
+    #[display(fmt = "memory at address {} is read-only", _0)]
+    WriteToReadOnly(usize),
+    #[display(fmt = "byte stack is empty")]
+    ByteStackUnderflow,
+    #[display(fmt = "no free memory block of {} bytes", _0)]
+    OutOfMemory(usize),
+    #[display(fmt = "recursion limit of {} nested calls exceeded", _0)]
+    RecursionLimit(usize),
+    #[display(fmt = "{} bytes of memory were never freed", leaked_bytes)]
+    MemoryLeak { leaked_bytes: usize },
+    // the compiled form runs to the end in one go, so nothing could resume a `yield` or watch the steps
+    #[display(fmt = "{} is not supported by compiled programs", _0)]
+    NotCompilable(&'static str),
+}
+
+impl From<std::io::Error> for RuntimeError {
+    fn from(error: std::io::Error) -> Self {
+        RuntimeError::Io(error.kind())
+    }
+}
+
+impl From<memory::MapError> for RuntimeError {
+    fn from(error: memory::MapError) -> Self {
+        match error {
+            memory::MapError::InvalidMap(address) => RuntimeError::InvalidMap(address),
+            memory::MapError::OutOfBounds(address) => RuntimeError::OutOfBounds(address),
+            memory::MapError::OutOfMemory(len) => RuntimeError::OutOfMemory(len),
+        }
+    }
+}
+
+impl From<memory::FreeError> for RuntimeError {
+    fn from(error: memory::FreeError) -> Self {
+        match error {
+            memory::FreeError::OutOfBounds(address) => RuntimeError::OutOfBounds(address),
+            memory::FreeError::NotAllocated(address) => RuntimeError::InvalidFree(address),
+        }
+    }
+}
+
+impl ParseErrorKind {
+    fn at(self, span: Span) -> ParseError {
+        ParseError { kind: self, span }
+    }
+}
+
+// returns the value of the environment variable with the given name
+pub type Env<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+// called before every token with the whole stack, the token is skipped when it returns false
+pub type Hook<'a> = &'a mut dyn FnMut(&Token, &[usize]) -> bool;
+
+// options controlling a single run of the interpreter
+#[derive(Default)]
+pub struct Config<'a> {
+    pub debug: Option<&'a mut dyn Write>, // sink for `???` dumps, the program writer is used when unset
+    pub check_leaks: bool, // fail when memory other than globals is still allocated at exit
+    pub entry_point: Option<&'a str>, // function to start from, `main` when unset
+    pub edit_distance_limit: Option<usize>, // EDIT_DISTANCE_LIMIT when unset
+    pub recursion_limit: Option<usize>, // most nested function calls allowed, RECURSION_LIMIT when unset
+    pub stats: Option<&'a mut RunStats>, // profile of the run, collected only when set
+    pub env: Option<Env<'a>>, // looks up `getenv` names, the process environment when unset
+    pub hook: Option<Hook<'a>>,
+    pub seed: Option<usize>, // initial state of `rand`, taken from the clock when unset
+    pub allocation_limit: Option<usize>, // most allocations other than globals that can be live at once, unlimited when unset
+    pub memory_limit: Option<usize>, // most bytes that can be allocated at once, including globals, 2^32 when unset
+    pub read_only_literals: bool, // fail when writing to the memory of string literals, until it is freed
+}
 
 #[derive(Debug)]
-enum InterpretationStatus {
-    Break,
-    Continue,
-    None,
+struct Function {
+    parameters: Vec<String>, // bound like `let` on every call, the last one taken from the top of the stack
+    pure: bool, // only touches the stack, so calls with known arguments can be evaluated ahead of time
+    results: Option<Vec<String>>, // names of the values left on the stack, when declared, checked against the body when parsing
+    body: Vec<Token>,
+}
+
+// what is parsed before the program is complete, shared by the files it includes
+#[derive(Default)]
+struct Definitions {
+    functions: Functions,
+    globals: Vec<usize>,
+    constants: HashMap<String, usize>, // names replaced by a fixed value, like the addresses of globals
+    calls: Vec<Call>, // functions called before they are defined in the file being parsed
+    including: Vec<PathBuf>, // files being parsed, each included by the one before it
+    included: HashSet<PathBuf>,
+}
+
+// a call to a function that wasn't defined when the call was parsed
+struct Call {
+    index: usize,
+    span: Span,
+    local: bool, // unqualified inside of a module, so it is to a function of an outer module when the module has none
+}
+
+impl Definitions {
+    fn into_program(self) -> Program {
+        let mut program = Program {
+            functions: self.functions,
+            globals: self.globals,
+        };
+        program.fold_pure_calls();
+        program
+    }
 }
 
 #[derive(Debug)]
 pub struct Program {
-    functions: HashMap<String, Vec<Token>>,
+    functions: Functions,
+    globals: Vec<usize>, // sizes of the global memory regions, allocated in order before the program runs
 }
 
 impl Program {
-    pub fn parse(code: &str) -> Self {
-        let mut functions = HashMap::new();
-        let mut code = Tokens::new(code).peekable();
-        while let Some(token) = code.next() {
+    pub fn parse(code: &str) -> Result<Self, ParseError> {
+        Self::parse_chars(code.chars())
+    }
+
+    // parses the source as it is read, without needing all of it in memory,
+    // included files are looked up relative to the working directory
+    pub fn parse_chars<'a>(code: impl Iterator<Item = char> + 'a) -> Result<Self, ParseError> {
+        let mut definitions = Definitions::default();
+        Self::parse_definitions(&mut Tokens::new(code), &mut definitions, Path::new(""))?;
+        Ok(definitions.into_program())
+    }
+
+    // parses the file, looking up the files it includes relative to it
+    pub fn parse_file(path: impl AsRef<Path>) -> Result<Self, ParseError> {
+        let mut definitions = Definitions::default();
+        Self::include(path.as_ref(), &mut definitions).map_err(|kind| match kind {
+            ParseErrorKind::Included { error, .. } => *error,
+            kind => kind.at(Span {
+                offset: 0,
+                line: 1,
+                column: 1,
+            }),
+        })?;
+        Ok(definitions.into_program())
+    }
+
+    // adds the functions of a program parsed separately, like a library shared by several programs,
+    // nothing is added when it fails. the functions of either program can't call the ones of the other
+    pub fn merge(&mut self, other: Program) -> Result<(), MergeError> {
+        if let Some(name) = other
+            .functions
+            .names()
+            .into_iter()
+            .find(|name| self.functions.contains_key(name))
+        {
+            return Err(MergeError::DuplicateFunction(name.to_string()));
+        }
+        if !self.globals.is_empty() && !other.globals.is_empty() {
+            return Err(MergeError::OverlappingGlobals);
+        }
+        self.functions.append(other.functions);
+        if self.globals.is_empty() {
+            self.globals = other.globals;
+        }
+        Ok(())
+    }
+
+    // adds the definitions of the file to the ones parsed so far, unless it was already included
+    fn include(path: &Path, definitions: &mut Definitions) -> Result<(), ParseErrorKind> {
+        let read_error = || ParseErrorKind::IncludeFailed(path.display().to_string());
+        let path = path.canonicalize().map_err(|_| read_error())?;
+        if definitions.including.contains(&path) {
+            return Err(ParseErrorKind::CircularInclude(path.display().to_string()));
+        }
+        if !definitions.included.insert(path.clone()) {
+            return Ok(());
+        }
+        let source = std::fs::read_to_string(&path).map_err(|_| read_error())?;
+        definitions.including.push(path.clone());
+        let directory = path.parent().unwrap_or(Path::new(""));
+        let result =
+            Self::parse_definitions(&mut Tokens::new(source.chars()), definitions, directory);
+        definitions.including.pop();
+        result.map_err(|error| ParseErrorKind::Included {
+            path: path.display().to_string(),
+            error: Box::new(error),
+        })
+    }
+
+    fn parse_definitions(
+        code: &mut Tokens,
+        definitions: &mut Definitions,
+        directory: &Path, // included files are looked up relative to it
+    ) -> Result<(), ParseError> {
+        // the calls of the file including this one are resolved once that file is parsed
+        let outer_calls = std::mem::take(&mut definitions.calls);
+        let mut defined = Vec::new();
+        let mut modules: Vec<String> = Vec::new(); // the ones the definitions are in, innermost last
+        while let Some((token, span)) = code.next() {
             match token.as_str() {
                 "fn" => {
-                    let function_name = code.next().unwrap();
-                    match code.next().as_deref() {
-                        Some("{") => {
-                            let function = Self::parse_code_segment(&mut code, &functions, &vec![]);
-                            functions.insert(function_name, function);
-                        }
-                        Some(token) => {
-                            panic!("unsupported symbol: {token}, '{{' expected");
-                        }
-                        None => {
-                            panic!("unexpected end of file");
-                        }
+                    let (function_name, name_span) = code.next_token()?;
+                    // the name is qualified by the modules, like `outer::inner::name`
+                    let function_name = modules
+                        .iter()
+                        .map(String::as_str)
+                        .chain([function_name.as_str()])
+                        .collect::<Vec<_>>()
+                        .join("::");
+                    if definitions.functions.contains_key(&function_name) {
+                        return Err(ParseErrorKind::DuplicateFunction(function_name).at(name_span));
+                    }
+                    let parameters = Self::parse_parameters(code)?;
+                    let results = match code.next_if(|token| token == "->") {
+                        Some(_) => Some(Self::parse_parameters(code)?),
+                        None => None,
+                    };
+                    let pure = code.next_if(|token| token == "pure").is_some();
+                    Self::expect_block(code)?;
+                    // declared before parsing the body, so that the function can call itself.
+                    // until then the body only calls itself, so that its stack effect is unknown
+                    let index = definitions.functions.reserve(&function_name);
+                    definitions.functions.insert(
+                        &function_name,
+                        Function {
+                            parameters: parameters.clone(),
+                            pure,
+                            results: results.clone(),
+                            body: vec![Token::FunctionCall(index)],
+                        },
+                    );
+                    let body = Self::parse_code_segment(
+                        code,
+                        &mut definitions.functions,
+                        &mut definitions.calls,
+                        &modules.join("::"),
+                        &parameters,
+                        &[],
+                        &definitions.constants,
+                    )?;
+                    defined.push((function_name.clone(), name_span));
+                    definitions.functions.insert(
+                        &function_name,
+                        Function {
+                            parameters,
+                            pure,
+                            results,
+                            body,
+                        },
+                    );
+                }
+                "global" => {
+                    let (name, name_span) = code.next_token()?;
+                    let (size, span) = code.next_token()?;
+                    let size = Self::parse_integer(&size, span)?
+                        .ok_or_else(|| ParseErrorKind::InvalidLiteral(size).at(span))?;
+                    if definitions.constants.contains_key(&name) {
+                        return Err(ParseErrorKind::DuplicateGlobal(name).at(name_span));
                     }
+                    // globals are the first thing allocated, so their addresses are known in advance
+                    definitions
+                        .constants
+                        .insert(name, definitions.globals.iter().sum());
+                    definitions.globals.push(size);
+                }
+                // the value is an integer or character literal, replacing the name wherever it is used later
+                "const" => {
+                    let (name, name_span) = code.next_token()?;
+                    let (value, span) = code.next_token()?;
+                    let value = Self::parse_case_label(&value, span)?;
+                    if definitions.constants.contains_key(&name) {
+                        return Err(ParseErrorKind::DuplicateConstant(name).at(name_span));
+                    }
+                    definitions.constants.insert(name, value);
+                }
+                // groups functions so that their names don't collide with the ones of other modules
+                "mod" => {
+                    let (name, _) = code.next_token()?;
+                    Self::expect_block(code)?;
+                    modules.push(name);
+                }
+                "}" if !modules.is_empty() => {
+                    modules.pop();
+                }
+                // a file already included is skipped, so libraries can include what they need.
+                // its definitions are outside of the modules the include is in
+                "include" => {
+                    let (path, span) = code.next_token()?;
+                    let file = path
+                        .strip_prefix('"')
+                        .and_then(|path| path.strip_suffix('"'))
+                        .ok_or_else(|| ParseErrorKind::InvalidLiteral(path.clone()).at(span))?;
+                    Self::include(&directory.join(file), definitions)
+                        .map_err(|kind| kind.at(span))?;
                 }
 
-                symbol => {
-                    panic!("umrecognised symbol on top level of program: {symbol}; Expected one of the following values: [fn]")
+                _ => {
+                    return Err(ParseErrorKind::UnexpectedToken {
+                        found: token,
+                        expected: "fn`, `mod`, `global`, `const` or `include",
+                    }
+                    .at(span))
                 }
             };
         }
+        if code.unterminated_comment.is_some() || !modules.is_empty() {
+            return Err(code.eof_error());
+        }
+        // calls may refer to functions defined later in the file, so they are resolved once all of them are parsed.
+        // the qualified calls are checked first, so that a local call can't redirect one to the same missing function
+        let mut calls = std::mem::replace(&mut definitions.calls, outer_calls);
+        calls.sort_by_key(|call| call.local);
+        let mut redirects = HashMap::new();
+        for call in calls {
+            let functions = &definitions.functions;
+            if functions.get_index(call.index).is_some() || redirects.contains_key(&call.index) {
+                continue;
+            }
+            let name = functions.name(call.index);
+            let found = call
+                .local
+                .then(|| Self::outer_function(functions, name))
+                .flatten();
+            let Some(found) = found else {
+                // a local call is reported by the name it was written with
+                let name = match call.local {
+                    true => name.rsplit("::").next().unwrap_or(name),
+                    false => name,
+                };
+                let name = name.to_string();
+                return Err(ParseErrorKind::UndefinedFunction(name).at(call.span));
+            };
+            redirects.insert(call.index, found);
+        }
+        definitions.functions.redirect(&redirects);
+        for (name, span) in defined {
+            Self::check_function(&definitions.functions, &name, span)?;
+        }
+        Ok(())
+    }
+
+    // consumes the opening brace of a block
+    fn expect_block(code: &mut Tokens) -> Result<(), ParseError> {
+        match code.next_token()? {
+            (token, _) if token == "{" => Ok(()),
+            (token, span) => Err(ParseErrorKind::UnexpectedToken {
+                found: token,
+                expected: "{",
+            }
+            .at(span)),
+        }
+    }
+
+    // parses the optional parenthesized list of parameter names following the function name
+    fn parse_parameters(code: &mut Tokens) -> Result<Vec<String>, ParseError> {
+        let mut parameters = Vec::new();
+        let Some((mut token, mut span)) = code.next_if(|token| token.starts_with('(')) else {
+            return Ok(parameters);
+        };
+        token.remove(0);
+        loop {
+            let closed = token.ends_with(')');
+            if closed {
+                token.pop();
+            }
+            if token.contains(['(', ')', '{', '}']) {
+                return Err(ParseErrorKind::UnexpectedToken {
+                    found: token,
+                    expected: "parameter name",
+                }
+                .at(span));
+            }
+            if !token.is_empty() {
+                parameters.push(token);
+            }
+            if closed {
+                return Ok(parameters);
+            }
+            (token, span) = code.next_token()?;
+        }
+    }
 
-        Self { functions }
+    // checks the purity and the number of results the function declares, once every function it calls is parsed
+    fn check_function(functions: &Functions, name: &str, span: Span) -> Result<(), ParseError> {
+        let function = &functions[name];
+        if let Some(token) = function
+            .pure
+            .then(|| Self::find_impure(&function.body, functions))
+            .flatten()
+        {
+            return Err(ParseErrorKind::ImpureFunction {
+                function: name.to_string(),
+                token: functions.display(token),
+            }
+            .at(span));
+        }
+        if let Some(results) = &function.results {
+            let found = Self::body_effect(functions, &function.body)
+                .ok_or_else(|| ParseErrorKind::UnknownStackEffect(name.to_string()).at(span))?;
+            if found != results.len() as isize {
+                return Err(ParseErrorKind::ResultCount {
+                    function: name.to_string(),
+                    expected: results.len(),
+                    found,
+                }
+                .at(span));
+            }
+        }
+        Ok(())
     }
 
-    // this function handles the parsing of funtion bodies
+    // this function handles the parsing of function bodies
     fn parse_code_segment(
-        code: &mut Peekable<Tokens>,
-        functions: &HashMap<String, Vec<Token>>,
+        code: &mut Tokens,
+        functions: &mut Functions,
+        calls: &mut Vec<Call>, // functions not defined yet, which may be defined later in the file
+        module: &str, // the qualified name of the module the segment is in, empty outside of modules
         lets: &Vec<String>,
-    ) -> Vec<Token> {
+        labels: &[String], // labels of the loops around the segment in the same function
+        constants: &HashMap<String, usize>, // names replaced by a fixed value, like the addresses of globals
+    ) -> Result<Vec<Token>, ParseError> {
         let mut tokens = Vec::new();
-        while let Some(token) = code.next() {
+        while let Some((token, span)) = code.next() {
             match token.as_str() {
                 // math operations
                 "+" => tokens.push(Token::Math(MathOperator::Add)),
                 "-" => tokens.push(Token::Math(MathOperator::Sub)),
                 "*" => tokens.push(Token::Math(MathOperator::Mul)),
+                "/" => tokens.push(Token::Math(MathOperator::Div)),
+                "%" => tokens.push(Token::Math(MathOperator::Mod)),
 
                 // boolean operations
                 "<" => tokens.push(Token::Cmp(CmpOperator::Less)),
                 ">" => tokens.push(Token::Cmp(CmpOperator::Greater)),
                 "=" => tokens.push(Token::Cmp(CmpOperator::Equal)),
+                // bitwise operations
+                "&" => tokens.push(Token::Bit(BitOperator::And)),
+                "|" => tokens.push(Token::Bit(BitOperator::Or)),
+                "^" => tokens.push(Token::Bit(BitOperator::Xor)),
+                "<<" => tokens.push(Token::Bit(BitOperator::Shl)),
+                ">>" => tokens.push(Token::Bit(BitOperator::Shr)),
+                "~" => tokens.push(Token::Bit(BitOperator::Not)),
+
+                // logic operations
+                "and" => tokens.push(Token::Logic(LogicOperator::And)),
+                "or" => tokens.push(Token::Logic(LogicOperator::Or)),
+                "not" => tokens.push(Token::Logic(LogicOperator::Not)),
+
+                // character operations
+                "isdigit" => tokens.push(Token::Char(CharOperation::IsDigit)),
+                "isalpha" => tokens.push(Token::Char(CharOperation::IsAlpha)),
+                "isspace" => tokens.push(Token::Char(CharOperation::IsSpace)),
+                "toupper" => tokens.push(Token::Char(CharOperation::ToUpper)),
+                "tolower" => tokens.push(Token::Char(CharOperation::ToLower)),
 
                 // stack operations
                 "dup" => tokens.push(Token::Stack(StackOperation::Dup)),
@@ -73,66 +611,176 @@ impl Program {
                 "over" => tokens.push(Token::Stack(StackOperation::Over)),
                 "rot" => tokens.push(Token::Stack(StackOperation::Rot)),
                 "drop" => tokens.push(Token::Stack(StackOperation::Drop)),
+                "nip" => tokens.push(Token::Stack(StackOperation::Nip)),
+                "tuck" => tokens.push(Token::Stack(StackOperation::Tuck)),
+                "2dup" => tokens.push(Token::Stack(StackOperation::TwoDup)),
+                "2drop" => tokens.push(Token::Stack(StackOperation::TwoDrop)),
+                "pick" => tokens.push(Token::Stack(StackOperation::Pick)),
+                "pushframe" => tokens.push(Token::Stack(StackOperation::PushFrame)),
+                "popframe" => tokens.push(Token::Stack(StackOperation::PopFrame)),
+                "depth" => tokens.push(Token::Stack(StackOperation::Depth)),
 
                 // control flow operations
-                "break" => tokens.push(Token::Break),
-                "continue" => tokens.push(Token::Continue),
-                "}" => return tokens,
-                "loop" => match code.next().as_deref() {
-                    Some("{") => {
-                        tokens.push(Token::LoopBlock(Self::parse_code_segment(
-                            code, functions, lets,
-                        )));
+                // a name following them is taken as a label when a loop around has it
+                "break" => tokens.push(Token::Break(Self::parse_label_use(code, labels))),
+                "continue" => tokens.push(Token::Continue(Self::parse_label_use(code, labels))),
+                "return" => tokens.push(Token::Return),
+                "yield" => tokens.push(Token::Yield),
+                "}" => return Ok(tokens),
+                "loop" => {
+                    let (label, labels) = Self::parse_label(code, labels)?;
+                    let body = Self::parse_code_segment(
+                        code, functions, calls, module, lets, &labels, constants,
+                    )?;
+                    tokens.push(Token::LoopBlock(body, label));
+                }
+                "if" => {
+                    Self::expect_block(code)?;
+                    let mut blocks = vec![Self::parse_code_segment(
+                        code, functions, calls, module, lets, labels, constants,
+                    )?];
+                    while code.next_if(|token| token == "elif").is_some() {
+                        Self::expect_block(code)?;
+                        blocks.push(Self::parse_code_segment(
+                            code, functions, calls, module, lets, labels, constants,
+                        )?);
                     }
-                    Some(token) => {
-                        panic!("unsupported symbol: {token}, '{{' expected",);
+                    let mut false_block = if code.next_if(|token| token == "else").is_some() {
+                        Self::expect_block(code)?;
+                        Self::parse_code_segment(
+                            code, functions, calls, module, lets, labels, constants,
+                        )?
+                    } else {
+                        vec![]
+                    };
+                    // every `elif` takes the next value from the stack as its condition, so it becomes
+                    // an `if` in the previous false block, and the branches taken before it drop its condition
+                    let conditions = blocks.len();
+                    for (index, block) in blocks.into_iter().enumerate().rev() {
+                        let mut true_block: Vec<Token> = (index + 1..conditions)
+                            .map(|_| Token::Stack(StackOperation::Drop))
+                            .collect();
+                        true_block.extend(block);
+                        false_block = vec![Token::IfBlock(true_block, false_block)];
+                    }
+                    tokens.extend(false_block);
+                }
+                "?" => {
+                    Self::expect_block(code)?;
+                    let true_block = Self::parse_code_segment(
+                        code, functions, calls, module, lets, labels, constants,
+                    )?;
+                    match code.next_token()? {
+                        (token, _) if token == ":" => {}
+                        (token, span) => {
+                            return Err(ParseErrorKind::UnexpectedToken {
+                                found: token,
+                                expected: ":",
+                            }
+                            .at(span))
+                        }
                     }
-                    None => {
-                        panic!("unexpected end of file");
+                    Self::expect_block(code)?;
+                    let false_block = Self::parse_code_segment(
+                        code, functions, calls, module, lets, labels, constants,
+                    )?;
+                    for block in [&true_block, &false_block] {
+                        if Self::body_effect(functions, block) != Some(1) {
+                            return Err(ParseErrorKind::ConditionalValue.at(span));
+                        }
                     }
-                },
-                "if" => match code.next().as_deref() {
-                    Some("{") => {
-                        let true_block = Self::parse_code_segment(code, functions, lets);
-                        let false_block = if code.next_if(|token| token == "else").is_some() {
-                            match code.next().as_deref() {
-                                Some("{") => Self::parse_code_segment(code, functions, lets),
-                                Some(token) => {
-                                    panic!("unsupported symbol: {token}, '{{' expected");
+                    tokens.push(Token::Conditional(true_block, false_block));
+                }
+                "match" => {
+                    Self::expect_block(code)?;
+                    let mut arms: Vec<(usize, Vec<Token>)> = Vec::new();
+                    let mut default = Vec::new();
+                    loop {
+                        match code.next_token()? {
+                            (token, _) if token == "case" => {
+                                let (label, span) = code.next_token()?;
+                                let value = Self::parse_case_label(&label, span)?;
+                                if arms.iter().any(|(other, _)| *other == value) {
+                                    return Err(ParseErrorKind::DuplicateCase(label).at(span));
                                 }
-                                None => {
-                                    panic!("unexpected end of file");
+                                Self::expect_block(code)?;
+                                let arm = Self::parse_code_segment(
+                                    code, functions, calls, module, lets, labels, constants,
+                                )?;
+                                arms.push((value, arm));
+                            }
+                            (token, _) if token == "else" => {
+                                Self::expect_block(code)?;
+                                default = Self::parse_code_segment(
+                                    code, functions, calls, module, lets, labels, constants,
+                                )?;
+                            }
+                            (token, _) if token == "}" => break,
+                            (token, span) => {
+                                return Err(ParseErrorKind::UnexpectedToken {
+                                    found: token,
+                                    expected: "case`, `else` or `}",
                                 }
+                                .at(span))
                             }
-                        } else {
-                            vec![]
-                        };
-                        tokens.push(Token::IfBlock(true_block, false_block));
-                    }
-                    Some(token) => {
-                        panic!("unsupported symbol: {token}, '{{' expected",);
-                    }
-                    None => {
-                        panic!("unexpected end of file");
+                        }
                     }
-                },
+                    let table = Self::jump_table(&arms);
+                    tokens.push(Token::MatchBlock(arms, default, table));
+                }
 
                 // TODO: replace this with proper output after access to memory and arrays are added to the language
                 "putc" => tokens.push(Token::Putc),
                 "putu" => tokens.push(Token::Putu),
+                "puti" => tokens.push(Token::Puti),
+                "puts" => tokens.push(Token::Puts),
+                "redirect" => tokens.push(Token::Redirect),
+                "endredirect" => tokens.push(Token::EndRedirect),
+                "getc" => tokens.push(Token::Getc),
+                "getu" => tokens.push(Token::Getu),
+                "readline" => tokens.push(Token::Readline),
+                "getenv" => tokens.push(Token::Getenv),
+                "rand" => tokens.push(Token::Rand),
+                "seed" => tokens.push(Token::Seed),
+                "getseed" => tokens.push(Token::GetSeed),
                 "???" => tokens.push(Token::Debug),
                 "<-" => tokens.push(Token::Memory(MemoryOperation::LoadByte)),
                 "->" => tokens.push(Token::Memory(MemoryOperation::StoreByte)),
+                "w<-" => tokens.push(Token::Memory(MemoryOperation::LoadWord)),
+                "w->" => tokens.push(Token::Memory(MemoryOperation::StoreWord)),
                 "alloc" => tokens.push(Token::Memory(MemoryOperation::Alloc)),
                 "free" => tokens.push(Token::Memory(MemoryOperation::Free)),
+                "incr" => tokens.push(Token::Memory(MemoryOperation::Increment)),
+                "decr" => tokens.push(Token::Memory(MemoryOperation::Decrement)),
+                "histogram" => tokens.push(Token::Memory(MemoryOperation::Histogram)),
+                "here" => tokens.push(Token::Memory(MemoryOperation::Here)),
+                "ralloc" => tokens.push(Token::Memory(MemoryOperation::CountedAlloc)),
+                "raddr" => tokens.push(Token::Memory(MemoryOperation::CountedAddress)),
+                "incref" => tokens.push(Token::Memory(MemoryOperation::IncrementReferences)),
+                "decref" => tokens.push(Token::Memory(MemoryOperation::DecrementReferences)),
+                "sinit" => tokens.push(Token::Memory(MemoryOperation::ByteStackInit)),
+                "spush" => tokens.push(Token::Memory(MemoryOperation::ByteStackPush)),
+                "spop" => tokens.push(Token::Memory(MemoryOperation::ByteStackPop)),
+                "map_new" => tokens.push(Token::Memory(MemoryOperation::MapNew)),
+                "map_set" => tokens.push(Token::Memory(MemoryOperation::MapSet)),
+                "map_get" => tokens.push(Token::Memory(MemoryOperation::MapGet)),
+                "memcpy" => tokens.push(Token::Memory(MemoryOperation::Copy)),
+                "memset" => tokens.push(Token::Memory(MemoryOperation::Fill)),
+                "editdist" => tokens.push(Token::Memory(MemoryOperation::EditDistance)),
+                "crlf2lf" => tokens.push(Token::Memory(MemoryOperation::CrlfToLf)),
+                "itoa_base" => tokens.push(Token::Memory(MemoryOperation::FormatBase)),
+                "crc32" => tokens.push(Token::Memory(MemoryOperation::Crc32)),
                 "let" => {
                     let mut let_bindings = Vec::new();
                     let mut new_lets = lets.clone();
-                    while let Some(token) = code.next() {
+                    loop {
+                        let (token, _) = code.next_token()?;
                         if token == "{" {
                             new_lets.extend(let_bindings.clone());
                             tokens.push(Token::LetBlock(
-                                Self::parse_code_segment(code, functions, &new_lets),
+                                Self::parse_code_segment(
+                                    code, functions, calls, module, &new_lets, labels, constants,
+                                )?,
                                 let_bindings,
                             ));
                             break;
@@ -142,331 +790,3464 @@ impl Program {
                     }
                 }
                 "while" => {
-                    if let Some("{") = code.next().as_deref() {
-                        let condition = Self::parse_code_segment(code, functions, lets);
-                        if let Some("{") = code.next().as_deref() {
-                            let loop_body = Self::parse_code_segment(code, functions, lets);
-                            tokens.push(Token::WhileBlock(condition, loop_body));
+                    let (label, labels) = Self::parse_label(code, labels)?;
+                    let condition = Self::parse_code_segment(
+                        code, functions, calls, module, lets, &labels, constants,
+                    )?;
+                    Self::expect_block(code)?;
+                    let loop_body = Self::parse_code_segment(
+                        code, functions, calls, module, lets, &labels, constants,
+                    )?;
+                    tokens.push(Token::WhileBlock(condition, loop_body, label));
+                }
+
+                "=>" => {
+                    let (name, span) = code.next_token()?;
+                    if !lets.contains(&name) {
+                        return Err(ParseErrorKind::UnexpectedToken {
+                            found: name,
+                            expected: "let binding",
                         }
+                        .at(span));
                     }
+                    tokens.push(Token::SetLet(name));
                 }
-
-                token => {
-                    if let Ok(value) = token.parse::<usize>() {
-                        tokens.push(Token::Push(value));
-                    } else if token.starts_with('"') && token.ends_with('"') {
-                        let mut data = token[1..token.len() - 1].as_bytes().to_vec();
-                        data.push(0);
-                        tokens.push(Token::Memory(MemoryOperation::PushBytes(data)));
-                    } else if let Some(_function) = functions.get(token) {
-                        tokens.push(Token::FunctionCall(token.to_string()));
-                    } else if lets.contains(&token.to_string()) {
-                        tokens.push(Token::Let(token.to_string()))
-                    } else {
-                        panic!("Unknown token: {token}",);
+                "for" => {
+                    let (variable, _) = code.next_token()?;
+                    if variable == "{" {
+                        let body = Self::parse_code_segment(
+                            code, functions, calls, module, lets, labels, constants,
+                        )?;
+                        tokens.push(Token::RepeatBlock(body));
+                        continue;
                     }
+                    let (start, span) = code.next_token()?;
+                    let start =
+                        Self::parse_word(&start, span, functions, calls, module, lets, constants)?;
+                    let (end, span) = code.next_token()?;
+                    let end =
+                        Self::parse_word(&end, span, functions, calls, module, lets, constants)?;
+                    Self::expect_block(code)?;
+                    let mut new_lets = lets.clone();
+                    new_lets.push(variable.clone());
+                    let body = Self::parse_code_segment(
+                        code, functions, calls, module, &new_lets, labels, constants,
+                    )?;
+                    tokens.push(Token::ForBlock(variable, vec![start], vec![end], body));
                 }
+
+                token => tokens.push(Self::parse_word(
+                    token, span, functions, calls, module, lets, constants,
+                )?),
             }
         }
-        tokens
+        Err(code.eof_error())
     }
 
-    pub fn interpret<W: Write>(&self, io: &mut Io<W>) {
-        let main = self
-            .functions
-            .get("main")
-            .expect("no main function provided");
-        self.interpret_segment(
-            main,
-            &mut Vec::with_capacity(1000),
-            &mut Memory::new(),
-            &HashMap::new(),
-            &mut InterpretationStatus::None,
-            io,
-        )
+    // the optional label between `loop` or `while` and the opening brace, with the labels visible inside
+    fn parse_label(
+        code: &mut Tokens,
+        labels: &[String],
+    ) -> Result<(Option<String>, Vec<String>), ParseError> {
+        let mut labels = labels.to_vec();
+        let label = match code.next_token()? {
+            (token, _) if token == "{" => None,
+            (token, _) => {
+                Self::expect_block(code)?;
+                labels.push(token.clone());
+                Some(token)
+            }
+        };
+        Ok((label, labels))
     }
 
-    fn interpret_segment<W: Write>(
-        &self,
-        segment: &[Token],
-        stack: &mut Vec<usize>,
-        memory: &mut Memory,
-        variables: &HashMap<String, usize>,
-        status: &mut InterpretationStatus,
-        io: &mut Io<W>,
-    ) {
-        for token in segment {
-            match token {
-                Token::Push(value) => {
-                    stack.push(*value);
-                }
-                Token::Math(operand) => {
-                    let b = stack.pop().unwrap();
-                    let a = stack.pop().unwrap();
-                    let result = match operand {
-                        MathOperator::Add => a + b,
-                        MathOperator::Sub => a - b,
-                        MathOperator::Mul => a * b,
-                    };
-                    stack.push(result);
-                }
-                Token::Cmp(operand) => {
-                    let b = stack.pop().unwrap();
-                    let a = stack.pop().unwrap();
-                    let result = match operand {
-                        CmpOperator::Less => a < b,
-                        CmpOperator::Greater => a > b,
-                        CmpOperator::Equal => a == b,
-                    };
-                    stack.push(result as usize);
-                }
-                Token::Stack(operand) => match operand {
-                    StackOperation::Dup => stack.push(*stack.last().unwrap()),
-                    StackOperation::Swap => {
-                        let a = stack.pop().unwrap();
-                        let b = stack.pop().unwrap();
-                        stack.push(a);
-                        stack.push(b);
-                    }
-                    StackOperation::Over => {
-                        let a = *stack.get(stack.len() - 2).unwrap();
-                        stack.push(a);
-                    }
-                    StackOperation::Rot => {
-                        let a = stack.pop().unwrap();
-                        let b = stack.pop().unwrap();
-                        let c = stack.pop().unwrap();
-                        stack.push(b);
-                        stack.push(a);
-                        stack.push(c);
-                    }
-                    StackOperation::Drop => {
-                        stack.pop();
-                    }
-                },
-                Token::Memory(operand) => match operand {
-                    MemoryOperation::PushBytes(data) => {
-                        let address = memory.extend(data);
-                        stack.push(address);
-                    }
-                    MemoryOperation::LoadByte => {
-                        let address = stack.pop().unwrap();
-                        let value = memory.get(address).unwrap();
-                        stack.push(*value as usize);
-                    }
-                    MemoryOperation::StoreByte => {
-                        let value = stack.pop().unwrap();
-                        let address = stack.pop().unwrap();
-                        memory.set(address, value as u8);
-                    }
-                    MemoryOperation::Free => {
-                        let len = stack.pop().unwrap();
-                        let address = stack.pop().unwrap();
-                        memory.remove(address, len);
-                    }
-                    MemoryOperation::Alloc => {
-                        let len = stack.pop().unwrap();
-                        let address = memory.alloc(len);
-                        stack.push(address);
-                    }
-                },
-                Token::Putc => {
-                    write!(
-                        io,
-                        "{}",
-                        char::from_u32(stack.pop().unwrap() as u32).unwrap()
-                    )
-                    .unwrap();
-                    io.flush().unwrap();
-                }
-                Token::Putu => {
-                    write!(io, "{}", stack.pop().unwrap()).unwrap();
-                    std::io::stdout().flush().unwrap();
-                }
-                Token::Debug => {
-                    writeln!(io, "{stack:?} {memory:?}").unwrap();
-                }
-                Token::IfBlock(true_block, false_block) => {
-                    let segment = if stack.pop().unwrap() != 0 {
-                        true_block
-                    } else {
-                        false_block
-                    };
-                    self.interpret_segment(segment, stack, memory, variables, status, io);
-                    match status {
-                        InterpretationStatus::None => {}
-                        _ => return,
-                    }
-                }
-                Token::LoopBlock(segment) => loop {
-                    self.interpret_segment(segment, stack, memory, variables, status, io);
-                    match status {
-                        InterpretationStatus::Continue => {
-                            *status = InterpretationStatus::None;
-                            continue;
-                        }
-                        InterpretationStatus::Break => {
-                            *status = InterpretationStatus::None;
-                            break;
-                        }
-                        _ => {}
-                    }
-                },
-                Token::WhileBlock(condition, segment) => loop {
-                    self.interpret_segment(condition, stack, memory, variables, status, io);
-                    if stack.pop().unwrap() == 0 {
-                        break;
-                    }
+    fn parse_label_use(code: &mut Tokens, labels: &[String]) -> Option<String> {
+        code.next_if(|token| labels.iter().any(|label| label == token))
+            .map(|(label, _)| label)
+    }
 
-                    self.interpret_segment(segment, stack, memory, variables, status, io);
-                    match status {
-                        InterpretationStatus::Continue => {
-                            *status = InterpretationStatus::None;
-                            continue;
-                        }
-                        InterpretationStatus::Break => {
-                            *status = InterpretationStatus::None;
-                            break;
-                        }
-                        _ => {}
-                    }
-                },
-                Token::Break => {
-                    *status = InterpretationStatus::Break;
-                    return;
-                }
+    // parses the tokens that are not keywords: literals and the names of functions, let bindings and constants
+    fn parse_word(
+        token: &str,
+        span: Span,
+        functions: &mut Functions,
+        calls: &mut Vec<Call>,
+        module: &str,
+        lets: &[String],
+        constants: &HashMap<String, usize>,
+    ) -> Result<Token, ParseError> {
+        let literal = Self::strip_separators(token, span)?;
+        if let Some(value) = Self::parse_integer(&literal, span)? {
+            Ok(Token::Push(value))
+        } else if let Ok(value) = literal.parse::<isize>() {
+            // negative literals are stored in two's complement
+            Ok(Token::Push(value as usize))
+        } else if token.len() > 1 && token.starts_with('"') && token.ends_with('"') {
+            let mut data = token.as_bytes()[1..token.len() - 1].to_vec();
+            data.push(0);
+            Ok(Token::Memory(MemoryOperation::PushBytes(data)))
+        } else if let Some(literal) = token.strip_prefix('\'') {
+            let char = Self::parse_char(literal)
+                .ok_or_else(|| ParseErrorKind::InvalidLiteral(token.to_string()).at(span))?;
+            Ok(Token::Push(char as usize))
+        } else if let Some(index) = Self::local_function(
+            functions,
+            calls,
+            module,
+            token,
+            span,
+            lets.iter().any(|name| name == token) || constants.contains_key(token),
+        ) {
+            Ok(Token::FunctionCall(index))
+        } else if functions.contains_key(token) {
+            Ok(Token::FunctionCall(functions.reserve(token)))
+        } else if lets.iter().any(|name| name == token) {
+            Ok(Token::Let(token.to_string()))
+        } else if let Some(value) = constants.get(token) {
+            Ok(Token::Push(*value))
+        } else if Self::is_name(token) {
+            let index = functions.reserve(token);
+            calls.push(Call {
+                index,
+                span,
+                local: false,
+            });
+            Ok(Token::FunctionCall(index))
+        } else {
+            Err(ParseErrorKind::UnknownToken(token.to_string()).at(span))
+        }
+    }
 
-                Token::Continue => {
-                    *status = InterpretationStatus::Continue;
-                    return;
-                }
+    // whether the token can be the name of a function, it doesn't need to be defined yet
+    fn is_name(token: &str) -> bool {
+        token.starts_with(|char: char| char.is_alphabetic() || char == '_')
+            && !["fn", "mod", "global", "const", "include"].contains(&token)
+    }
 
-                Token::FunctionCall(function) => self.interpret_segment(
-                    self.functions
-                        .get(function)
-                        .expect("no function with this name found"),
-                    stack,
-                    memory,
-                    variables,
-                    status,
-                    io,
-                ),
-                Token::LetBlock(segment, let_bindings) => {
-                    let mut new_variables = variables.clone();
-                    for let_binding in let_bindings {
-                        new_variables.insert(let_binding.clone(), stack.pop().unwrap());
-                    }
-                    self.interpret_segment(segment, stack, memory, &new_variables, status, io);
-                    match status {
-                        InterpretationStatus::None => {}
-                        _ => return,
-                    }
+    // an unqualified name inside of a module calls the function of the module with it, even when it is defined later,
+    // unless it is bound by a let or a constant. None for the names that are not calls of such functions
+    fn local_function(
+        functions: &mut Functions,
+        calls: &mut Vec<Call>,
+        module: &str,
+        token: &str,
+        span: Span,
+        bound: bool,
+    ) -> Option<usize> {
+        if module.is_empty() || token.contains("::") || !Self::is_name(token) {
+            return None;
+        }
+        let local = format!("{module}::{token}");
+        if functions.contains_key(&local) {
+            return functions.index(&local);
+        }
+        if bound {
+            return None;
+        }
+        let index = functions.reserve(&local);
+        if functions.get_index(index).is_none() {
+            calls.push(Call {
+                index,
+                span,
+                local: true,
+            });
+        }
+        Some(index)
+    }
+
+    // the function a local call of the qualified name is to when its own module has none with the name,
+    // looking in the outer modules first
+    fn outer_function(functions: &Functions, name: &str) -> Option<usize> {
+        let segments: Vec<&str> = name.split("::").collect();
+        let (name, modules) = segments.split_last()?;
+        (0..modules.len()).rev().find_map(|depth| {
+            let candidate = modules[..depth]
+                .iter()
+                .chain([name])
+                .copied()
+                .collect::<Vec<_>>()
+                .join("::");
+            functions
+                .index(&candidate)
+                .filter(|&index| functions.get_index(index).is_some())
+        })
+    }
+
+    // parses decimal and prefixed unsigned integer literals, returning None for any other token
+    fn parse_integer(token: &str, span: Span) -> Result<Option<usize>, ParseError> {
+        if let Some((radix, digits)) = Self::radix_prefix(token) {
+            if digits.starts_with('+') {
+                return Err(ParseErrorKind::InvalidLiteral(token.to_string()).at(span));
+            }
+            match usize::from_str_radix(digits, radix) {
+                Ok(value) => Ok(Some(value)),
+                Err(error) if *error.kind() == IntErrorKind::PosOverflow => {
+                    Err(ParseErrorKind::IntegerLiteralOverflow(token.to_string()).at(span))
                 }
-                Token::Let(let_binding) => {
-                    let value = variables.get(let_binding).unwrap();
-                    stack.push(*value);
+                Err(_) => Err(ParseErrorKind::InvalidLiteral(token.to_string()).at(span)),
+            }
+        } else {
+            // negative literals are handled by the caller, only their overflow is reported here
+            let error = match token.strip_prefix('-') {
+                Some(_) => token.parse::<isize>().err(),
+                None => token.parse::<usize>().err(),
+            };
+            match error.as_ref().map(|error| error.kind()) {
+                None => Ok(token.parse::<usize>().ok()),
+                Some(IntErrorKind::PosOverflow | IntErrorKind::NegOverflow) => {
+                    Err(ParseErrorKind::IntegerLiteralOverflow(token.to_string()).at(span))
                 }
+                Some(_) => Ok(None),
             }
         }
     }
-}
 
-struct Tokens<'a> {
-    code: std::str::Chars<'a>,
-}
+    // case labels are integer or character literals
+    fn parse_case_label(token: &str, span: Span) -> Result<usize, ParseError> {
+        let literal = Self::strip_separators(token, span)?;
+        if let Some(value) = Self::parse_integer(&literal, span)? {
+            return Ok(value);
+        }
+        literal
+            .parse::<isize>()
+            .ok()
+            .map(|value| value as usize)
+            .or_else(|| Self::parse_char(token.strip_prefix('\'')?).map(|char| char as usize))
+            .ok_or_else(|| ParseErrorKind::InvalidLiteral(token.to_string()).at(span))
+    }
 
-impl<'a> Tokens<'a> {
-    pub fn new(code: &'a str) -> Self {
-        Tokens { code: code.chars() }
+    // removes the `_` separating the digits of an integer literal like `1_000` or `0xFF_FF`,
+    // a separator that is not between two digits makes the literal invalid
+    fn strip_separators(token: &str, span: Span) -> Result<Cow<'_, str>, ParseError> {
+        let unsigned = token.strip_prefix('-').unwrap_or(token);
+        let numeric = unsigned
+            .trim_start_matches('_')
+            .starts_with(|char: char| char.is_ascii_digit())
+            && unsigned
+                .chars()
+                .all(|char| char.is_ascii_alphanumeric() || char == '_');
+        if !numeric || !unsigned.contains('_') {
+            return Ok(Cow::Borrowed(token));
+        }
+        let digits = Self::radix_prefix(unsigned).map_or(unsigned, |(_, digits)| digits);
+        if digits.starts_with('_') || digits.ends_with('_') || digits.contains("__") {
+            return Err(ParseErrorKind::InvalidLiteral(token.to_string()).at(span));
+        }
+        Ok(Cow::Owned(token.replace('_', "")))
     }
-}
 
-impl<'a> Iterator for Tokens<'a> {
-    type Item = String;
+    // builds a table when at least half of the values between the smallest and the largest label are handled
+    fn jump_table(arms: &[(usize, Vec<Token>)]) -> Option<JumpTable> {
+        let start = arms.iter().map(|(label, _)| *label).min()?;
+        let end = arms.iter().map(|(label, _)| *label).max()?;
+        if arms.len() < 3 || end - start >= arms.len() * 2 {
+            return None;
+        }
+        let mut table = vec![None; end - start + 1];
+        for (index, (label, _)) in arms.iter().enumerate() {
+            table[label - start] = Some(index);
+        }
+        Some(JumpTable { start, arms: table })
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let mut accumulator = String::new();
-        let mut last_char = ' ';
-        let mut is_comment = false;
-        let separators = [' ', '\n', '\t'];
-
-        while let Some(char) = self.code.next() {
-            match char {
-                '\n' if is_comment => is_comment = false,
-                // this allows not to check for comments in the parsing function, as it consumes the iterator until the next buffer
-                '/' if last_char == '/' => {
-                    accumulator.pop();
-                    is_comment = true
-                }
-                '"' if !is_comment => {
-                    accumulator.push('"');
-                    for char in self.code.by_ref() {
-                        accumulator.push(char);
-                        if char == '"' {
-                            return Some(accumulator);
-                        }
+    // parses the body of a character literal following the opening quote
+    fn parse_char(literal: &str) -> Option<char> {
+        let mut chars = literal.strip_suffix('\'')?.chars();
+        let char = match chars.next()? {
+            '\\' => match chars.next()? {
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                '0' => '\0',
+                '\\' => '\\',
+                '\'' => '\'',
+                _ => return None,
+            },
+            char => char,
+        };
+        chars.next().is_none().then_some(char)
+    }
+
+    // splits off the `0x`, `0b` or `0o` prefix of an integer literal, in either case
+    fn radix_prefix(token: &str) -> Option<(u32, &str)> {
+        let radix = match token.get(..2)? {
+            "0x" | "0X" => 16,
+            "0b" | "0B" => 2,
+            "0o" | "0O" => 8,
+            _ => return None,
+        };
+        Some((radix, &token[2..]))
+    }
+
+    // the first token a pure function may not contain: anything touching memory or io, or calling an impure function
+    fn find_impure<'a>(segment: &'a [Token], functions: &Functions) -> Option<&'a Token> {
+        segment.iter().find_map(|token| match token {
+            Token::Push(_)
+            | Token::Math(_)
+            | Token::Cmp(_)
+            | Token::Bit(_)
+            | Token::Stack(_)
+            | Token::Logic(_)
+            | Token::Char(_)
+            | Token::Continue(_)
+            | Token::Break(_)
+            | Token::Return
+            | Token::Let(_)
+            | Token::SetLet(_) => None,
+            Token::Memory(_)
+            | Token::Putc
+            | Token::Putu
+            | Token::Puti
+            | Token::Puts
+            | Token::Redirect
+            | Token::EndRedirect
+            | Token::Getc
+            | Token::Getu
+            | Token::Readline
+            | Token::Getenv
+            | Token::Rand
+            | Token::Seed
+            | Token::GetSeed
+            | Token::Yield
+            | Token::Debug => Some(token),
+            Token::FunctionCall(index) => (!functions[*index].pure).then_some(token),
+            Token::IfBlock(first, second)
+            | Token::Conditional(first, second)
+            | Token::WhileBlock(first, second, _) => {
+                Self::find_impure(first, functions).or_else(|| Self::find_impure(second, functions))
+            }
+            Token::LoopBlock(segment, _)
+            | Token::RepeatBlock(segment)
+            | Token::LetBlock(segment, _) => Self::find_impure(segment, functions),
+            Token::ForBlock(_, start, end, segment) => [start, end, segment]
+                .into_iter()
+                .find_map(|segment| Self::find_impure(segment, functions)),
+            Token::MatchBlock(arms, default, _) => arms
+                .iter()
+                .find_map(|(_, arm)| Self::find_impure(arm, functions))
+                .or_else(|| Self::find_impure(default, functions)),
+        })
+    }
+
+    // replaces the calls to pure functions whose arguments are all literals with the values they return
+    fn fold_pure_calls(&mut self) {
+        for index in 0..self.functions.len() {
+            let Some(function) = self.functions.get_mut(index) else {
+                continue;
+            };
+            let mut body = std::mem::take(&mut function.body);
+            self.fold_segment(&mut body, index);
+            self.functions.get_mut(index).unwrap().body = body;
+        }
+    }
+
+    // the body of `function` is taken out while it is folded, so calls to it are left alone
+    fn fold_segment(&self, segment: &mut Vec<Token>, function: usize) {
+        let mut folded = Vec::with_capacity(segment.len());
+        for mut token in segment.drain(..) {
+            match &mut token {
+                Token::IfBlock(first, second)
+                | Token::Conditional(first, second)
+                | Token::WhileBlock(first, second, _) => {
+                    self.fold_segment(first, function);
+                    self.fold_segment(second, function);
+                }
+                Token::LoopBlock(segment, _)
+                | Token::RepeatBlock(segment)
+                | Token::LetBlock(segment, _) => self.fold_segment(segment, function),
+                Token::ForBlock(_, start, end, segment) => {
+                    for segment in [start, end, segment] {
+                        self.fold_segment(segment, function);
                     }
                 }
-                // WARNING: current next_token fails to parse code like: "fn main{}"; whitespace is required
-                char if separators.contains(&char) => {
-                    if !is_comment && !accumulator.is_empty() {
-                        return Some(accumulator);
+                Token::MatchBlock(arms, default, _) => {
+                    for (_, arm) in arms {
+                        self.fold_segment(arm, function);
                     }
+                    self.fold_segment(default, function);
                 }
-
-                char if !is_comment => {
-                    last_char = char;
-                    accumulator.push(char)
+                Token::FunctionCall(index) if *index != function => {
+                    if let Some((consumed, values)) = self.evaluate_pure_call(*index, &folded) {
+                        folded.truncate(folded.len() - consumed);
+                        folded.extend(values.into_iter().map(Token::Push));
+                        continue;
+                    }
                 }
                 _ => {}
             }
+            folded.push(token);
         }
-        None
+        *segment = folded;
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::{Tokens, Program, Io};
-    #[test]
-    fn test_next_token() {
-        let string = r#"
-        //test
-        fn main {
-            hello
-            "test string"
+    // runs the call on the literals before it, returning how many of them were replaced and with what.
+    // only functions without loops and calls are evaluated, so that evaluation always terminates,
+    // and the call is left to run when it takes longer or more memory than FOLD_LIMIT allows
+    fn evaluate_pure_call(&self, index: usize, preceding: &[Token]) -> Option<(usize, Vec<usize>)> {
+        let function = self.functions.get_index(index)?;
+        if !function.pure || !Self::is_straight(&function.body) {
+            return None;
         }
-    "#;
-        let code = &mut Tokens::new(string);
-        assert_eq!(code.next(), Some(String::from("fn")));
-        assert_eq!(code.next(), Some(String::from("main")));
-        assert_eq!(code.next(), Some(String::from("{")));
-        assert_eq!(code.next(), Some(String::from("hello")));
-        assert_eq!(code.next(), Some(String::from("\"test string\"")));
-        assert_eq!(code.next(), Some(String::from("}")));
-        assert_eq!(code.next(), None);
+        let literals: Vec<usize> = preceding
+            .iter()
+            .rev()
+            .map_while(|token| match token {
+                Token::Push(value) => Some(*value),
+                _ => None,
+            })
+            .collect();
+        let mut stack = Stack::new();
+        stack.extend(literals.iter().rev().copied());
+        let mut config = Config {
+            entry_point: Some(self.functions.name(index)),
+            recursion_limit: Some(FOLD_LIMIT),
+            memory_limit: Some(self.globals.iter().sum::<usize>() + FOLD_LIMIT),
+            allocation_limit: Some(0),
+            ..Config::default()
+        };
+        let mut io = Io::new(std::io::sink());
+        let mut machine = Machine::with_stack(self, stack, &mut config).ok()?;
+        let mut steps = 0;
+        while machine.step(&mut io, &mut config).ok()? == StepResult::Running {
+            steps += 1;
+            if steps > FOLD_LIMIT {
+                return None;
+            }
+        }
+        Some((literals.len(), machine.into_stack().into_values()?))
     }
 
-    macro_rules! test_program_output {
-        ($code: expr, $output: expr) => {{
-            let program = Program::parse($code);
-            let mut writer = vec![];
-            let mut io = Io::new(&mut writer);
-            program.interpret(&mut io);
-            assert_eq!(writer, $output);
-        }};
+    // whether the segment has no loops and no calls, nor a break or continue that could leave the caller's loop
+    fn is_straight(segment: &[Token]) -> bool {
+        segment.iter().all(|token| match token {
+            Token::LoopBlock(..) | Token::WhileBlock(..) | Token::FunctionCall(_) => false,
+            // the counts come from the literals, so the loops can take arbitrarily long to end
+            Token::RepeatBlock(_) | Token::ForBlock(..) => false,
+            Token::Break(_) | Token::Continue(_) => false,
+            // the literals are all the evaluation sees of the stack
+            Token::Stack(StackOperation::Depth) => false,
+            Token::IfBlock(first, second) | Token::Conditional(first, second) => {
+                Self::is_straight(first) && Self::is_straight(second)
+            }
+            Token::LetBlock(segment, _) => Self::is_straight(segment),
+            Token::MatchBlock(arms, default, _) => {
+                arms.iter().all(|(_, arm)| Self::is_straight(arm)) && Self::is_straight(default)
+            }
+            _ => true,
+        })
     }
 
-    #[test]
-    fn test_interpreter() {
-        test_program_output!(
-            r#"
-        fn main {
-            69 putu
-            10 putc
+    // renders the parsed functions back into source-like text, one token per line
+    pub fn disassemble(&self) -> String {
+        let mut output = String::new();
+        for name in self.functions.names() {
+            let function = &self.functions[name];
+            output.push_str(&format!("fn {name} "));
+            if !function.parameters.is_empty() {
+                output.push_str(&format!("({}) ", function.parameters.join(" ")));
+            }
+            if let Some(results) = &function.results {
+                output.push_str(&format!("-> ({}) ", results.join(" ")));
+            }
+            if function.pure {
+                output.push_str("pure ");
+            }
+            output.push_str("{\n");
+            self.disassemble_segment(&function.body, 1, &mut output);
+            output.push_str("}\n");
         }
-        "#,
-            "69\n".as_bytes()
-        );
+        output
+    }
+
+    fn disassemble_segment(&self, segment: &[Token], depth: usize, output: &mut String) {
+        let indent = "    ".repeat(depth);
+        for token in segment {
+            match token {
+                Token::IfBlock(true_block, false_block) => {
+                    output.push_str(&format!("{indent}if {{\n"));
+                    self.disassemble_segment(true_block, depth + 1, output);
+                    if !false_block.is_empty() {
+                        output.push_str(&format!("{indent}}} else {{\n"));
+                        self.disassemble_segment(false_block, depth + 1, output);
+                    }
+                }
+                Token::Conditional(true_block, false_block) => {
+                    output.push_str(&format!("{indent}? {{\n"));
+                    self.disassemble_segment(true_block, depth + 1, output);
+                    output.push_str(&format!("{indent}}} : {{\n"));
+                    self.disassemble_segment(false_block, depth + 1, output);
+                }
+                Token::ForBlock(variable, start, end, segment) => {
+                    let [start, end] = [start, end].map(|bound| {
+                        bound
+                            .iter()
+                            .map(|token| self.functions.display(token))
+                            .collect::<Vec<_>>()
+                            .join(" ")
+                    });
+                    output.push_str(&format!("{indent}for {variable} {start} {end} {{\n"));
+                    self.disassemble_segment(segment, depth + 1, output);
+                }
+                Token::RepeatBlock(segment) => {
+                    output.push_str(&format!("{indent}for {{\n"));
+                    self.disassemble_segment(segment, depth + 1, output);
+                }
+                Token::MatchBlock(arms, default, _) => {
+                    output.push_str(&format!("{indent}match {{\n"));
+                    for (label, arm) in arms {
+                        output.push_str(&format!("{indent}    case {label} {{\n"));
+                        self.disassemble_segment(arm, depth + 2, output);
+                        output.push_str(&format!("{indent}    }}\n"));
+                    }
+                    if !default.is_empty() {
+                        output.push_str(&format!("{indent}    else {{\n"));
+                        self.disassemble_segment(default, depth + 2, output);
+                        output.push_str(&format!("{indent}    }}\n"));
+                    }
+                }
+                Token::LoopBlock(segment, label) => {
+                    let label = label
+                        .as_ref()
+                        .map_or(String::new(), |label| format!("{label} "));
+                    output.push_str(&format!("{indent}loop {label}{{\n"));
+                    self.disassemble_segment(segment, depth + 1, output);
+                }
+                Token::WhileBlock(condition, segment, label) => {
+                    let label = label
+                        .as_ref()
+                        .map_or(String::new(), |label| format!("{label} "));
+                    output.push_str(&format!("{indent}while {label}{{\n"));
+                    self.disassemble_segment(condition, depth + 1, output);
+                    output.push_str(&format!("{indent}}} {{\n"));
+                    self.disassemble_segment(segment, depth + 1, output);
+                }
+                Token::LetBlock(segment, let_bindings) => {
+                    output.push_str(&format!("{indent}let {} {{\n", let_bindings.join(" ")));
+                    self.disassemble_segment(segment, depth + 1, output);
+                }
+                Token::Break(Some(label)) | Token::Continue(Some(label)) => {
+                    output.push_str(&format!("{indent}{token} {label}\n"));
+                    continue;
+                }
+                token => {
+                    let token = self.functions.display(token);
+                    output.push_str(&format!("{indent}{token}\n"));
+                    continue;
+                }
+            }
+            output.push_str(&format!("{indent}}}\n"));
+        }
+    }
+
+    // names of the functions defined by the program, sorted
+    pub fn function_names(&self) -> Vec<&str> {
+        self.functions.names()
+    }
+
+    // checks that the program can be run: `main` is defined, and so is every function it calls.
+    // parsing doesn't require `main`, as libraries have none
+    pub fn validate(&self) -> Result<(), ParseErrorKind> {
+        if !self.functions.contains_key("main") {
+            return Err(ParseErrorKind::MissingMain);
+        }
+        let call_graph = self.call_graph();
+        for name in self.function_names() {
+            if let Some(call) = call_graph[name]
+                .iter()
+                .find(|call| !self.functions.contains_key(call))
+            {
+                return Err(ParseErrorKind::UndefinedFunction(call.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    // maps every function to the sorted list of functions it calls
+    pub fn call_graph(&self) -> HashMap<String, Vec<String>> {
+        self.functions
+            .iter()
+            .map(|(_, name, function)| {
+                let mut calls = Vec::new();
+                Self::collect_calls(&function.body, &mut calls);
+                let mut calls: Vec<String> = calls
+                    .into_iter()
+                    .map(|index| self.functions.name(index).to_string())
+                    .collect();
+                calls.sort_unstable();
+                calls.dedup();
+                (name.to_string(), calls)
+            })
+            .collect()
+    }
+
+    // functions that can not be reached from any of the roots, sorted by name
+    pub fn unreachable_functions(&self, roots: &[&str]) -> Vec<String> {
+        let call_graph = self.call_graph();
+        let mut reachable = HashSet::new();
+        let mut pending: Vec<&str> = roots.to_vec();
+        while let Some(function) = pending.pop() {
+            if let Some(calls) = call_graph.get(function) {
+                if reachable.insert(function) {
+                    pending.extend(calls.iter().map(String::as_str));
+                }
+            }
+        }
+        self.functions
+            .names()
+            .into_iter()
+            .filter(|function| !reachable.contains(function))
+            .map(str::to_string)
+            .collect()
+    }
+
+    // removes the unreachable functions, returning their names
+    pub fn prune_unreachable(&mut self, roots: &[&str]) -> Vec<String> {
+        let unreachable = self.unreachable_functions(roots);
+        // nothing reachable calls the unreachable functions, so they can be removed
+        self.functions
+            .retain(|function| !unreachable.iter().any(|name| name == function));
+        unreachable
+    }
+
+    fn collect_calls(segment: &[Token], calls: &mut Vec<usize>) {
+        for token in segment {
+            match token {
+                Token::FunctionCall(index) => calls.push(*index),
+                Token::IfBlock(true_block, false_block)
+                | Token::Conditional(true_block, false_block) => {
+                    Self::collect_calls(true_block, calls);
+                    Self::collect_calls(false_block, calls);
+                }
+                Token::WhileBlock(condition, segment, _) => {
+                    Self::collect_calls(condition, calls);
+                    Self::collect_calls(segment, calls);
+                }
+                Token::ForBlock(_, start, end, segment) => {
+                    Self::collect_calls(start, calls);
+                    Self::collect_calls(end, calls);
+                    Self::collect_calls(segment, calls);
+                }
+                Token::MatchBlock(arms, default, _) => {
+                    for (_, arm) in arms {
+                        Self::collect_calls(arm, calls);
+                    }
+                    Self::collect_calls(default, calls);
+                }
+                Token::LoopBlock(segment, _)
+                | Token::RepeatBlock(segment)
+                | Token::LetBlock(segment, _) => Self::collect_calls(segment, calls),
+                _ => {}
+            }
+        }
+    }
+
+    pub fn interpret<W: Write, R: Read>(&self, io: &mut Io<W, R>) -> Result<(), RuntimeError> {
+        self.interpret_with_config(io, &mut Config::default())
+    }
+
+    // for hosts that treat any runtime error as fatal
+    pub fn interpret_or_panic<W: Write, R: Read>(&self, io: &mut Io<W, R>) {
+        if let Err(error) = self.interpret(io) {
+            panic!("error: {error}");
+        }
+    }
+
+    pub fn interpret_with_config<W: Write, R: Read>(
+        &self,
+        io: &mut Io<W, R>,
+        config: &mut Config,
+    ) -> Result<(), RuntimeError> {
+        self.run_with_config(io, config).map(|_| ())
+    }
+
+    // like `interpret`, but returns the values left on the stack, bottom first
+    pub fn interpret_into<W: Write, R: Read>(
+        &self,
+        io: &mut Io<W, R>,
+    ) -> Result<Vec<usize>, RuntimeError> {
+        Ok(self.run(io)?.stack().to_vec())
+    }
+
+    // like `interpret_into`, but the entry point starts with the initial values on the stack, the last one on top,
+    // so a single function can be run on chosen arguments by making it the whole of `main`
+    pub fn interpret_with_stack<W: Write, R: Read>(
+        &self,
+        initial: Vec<usize>,
+        io: &mut Io<W, R>,
+    ) -> Result<Vec<usize>, RuntimeError> {
+        self.call("main", initial, io)
+    }
+
+    // runs the function on the values of the stack, the last one on top, returning the values it leaves
+    pub fn call<W: Write, R: Read>(
+        &self,
+        name: &str,
+        stack: Vec<usize>,
+        io: &mut Io<W, R>,
+    ) -> Result<Vec<usize>, RuntimeError> {
+        if !self.functions.contains_key(name) {
+            return Err(RuntimeError::UnknownFunction(name.to_string()));
+        }
+        let mut initial = Stack::new();
+        initial.extend(stack);
+        let mut config = Config {
+            entry_point: Some(name),
+            ..Default::default()
+        };
+        let machine = self.run_from(initial, io, &mut config)?;
+        Ok(machine.stack().to_vec())
+    }
+
+    // like `interpret`, but returns the finished machine, so that its stack and memory can be inspected
+    pub fn run<W: Write, R: Read>(&self, io: &mut Io<W, R>) -> Result<Machine<'_>, RuntimeError> {
+        self.run_with_config(io, &mut Config::default())
+    }
+
+    pub fn run_with_config<W: Write, R: Read>(
+        &self,
+        io: &mut Io<W, R>,
+        config: &mut Config,
+    ) -> Result<Machine<'_>, RuntimeError> {
+        self.run_from(Stack::new(), io, config)
+    }
+
+    fn run_from<W: Write, R: Read>(
+        &self,
+        stack: Stack,
+        io: &mut Io<W, R>,
+        config: &mut Config,
+    ) -> Result<Machine<'_>, RuntimeError> {
+        let mut machine = Machine::with_stack(self, stack, config)?;
+        while machine.step(io, config)? != StepResult::Finished {}
+        self.check_leaks(machine.memory(), config)?;
+        Ok(machine)
+    }
+
+    // anything left allocated besides the globals is leaked, an error when the config checks for leaks
+    fn check_leaks(&self, memory: &Memory, config: &Config) -> Result<(), RuntimeError> {
+        if !config.check_leaks {
+            return Ok(());
+        }
+        // the program may have freed some of its globals
+        let leaked_bytes = memory
+            .allocated()
+            .saturating_sub(self.globals.iter().sum::<usize>());
+        if leaked_bytes != 0 {
+            return Err(RuntimeError::MemoryLeak { leaked_bytes });
+        }
+        Ok(())
+    }
+}
+
+// splitmix64, advancing the state and returning the next value
+fn next_random(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E3779B97F4A7C15);
+    let mut value = *state;
+    value = (value ^ (value >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94D049BB133111EB);
+    value ^ (value >> 31)
+}
+
+// lowercase digits of the value, most significant first, None for bases outside of 2 to 36
+fn format_base(mut value: usize, base: usize) -> Option<Vec<u8>> {
+    if !(2..=36).contains(&base) {
+        return None;
+    }
+    let mut digits = Vec::new();
+    loop {
+        let digit = char::from_digit((value % base) as u32, base as u32)?;
+        digits.push(digit as u8);
+        value /= base;
+        if value == 0 {
+            break;
+        }
+    }
+    digits.reverse();
+    Some(digits)
+}
+
+// the reflected crc-32 with the polynomial 0x04C11DB7, computed a bit at a time without a table
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB88320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+// levenshtein distance, keeping only the previous row of the table
+fn edit_distance(a: &[u8], b: &[u8]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, a) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != b);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+struct Tokens<'a> {
+    code: Box<dyn Iterator<Item = char> + 'a>,
+    ahead: VecDeque<char>, // characters taken from `code` to look ahead, but not scanned yet
+    position: Span,
+    peeked: Option<Option<(String, Span)>>,
+    unterminated_comment: Option<Span>, // start of the block comment that ran into the end of file
+}
+
+impl<'a> Tokens<'a> {
+    pub fn new(code: impl Iterator<Item = char> + 'a) -> Self {
+        Tokens {
+            code: Box::new(code),
+            ahead: VecDeque::new(),
+            position: Span {
+                offset: 0,
+                line: 1,
+                column: 1,
+            },
+            peeked: None,
+            unterminated_comment: None,
+        }
+    }
+
+    // like `next`, but reaching the end of file is an error
+    fn next_token(&mut self) -> Result<(String, Span), ParseError> {
+        self.next().ok_or_else(|| self.eof_error())
+    }
+
+    fn eof_error(&self) -> ParseError {
+        match self.unterminated_comment {
+            Some(span) => ParseErrorKind::UnterminatedComment.at(span),
+            None => ParseErrorKind::UnexpectedEof.at(self.position),
+        }
+    }
+
+    fn at_comment(&mut self) -> bool {
+        self.starts_with("//") || self.starts_with("/*")
+    }
+
+    // whether the characters that are not scanned yet begin with `prefix`
+    fn starts_with(&mut self, prefix: &str) -> bool {
+        let len = prefix.chars().count();
+        while self.ahead.len() < len {
+            match self.code.next() {
+                Some(char) => self.ahead.push_back(char),
+                None => return false,
+            }
+        }
+        self.ahead
+            .iter()
+            .copied()
+            .zip(prefix.chars())
+            .all(|(a, b)| a == b)
+    }
+
+    fn next_if(&mut self, func: impl FnOnce(&str) -> bool) -> Option<(String, Span)> {
+        match self.next() {
+            Some(token) if func(&token.0) => Some(token),
+            token => {
+                self.peeked = Some(token);
+                None
+            }
+        }
+    }
+
+    fn next_char(&mut self) -> Option<char> {
+        let char = self.ahead.pop_front().or_else(|| self.code.next())?;
+        self.position.offset += char.len_utf8();
+        if char == '\n' {
+            self.position.line += 1;
+            self.position.column = 1;
+        } else {
+            self.position.column += 1;
+        }
+        Some(char)
+    }
+
+    fn peek_char(&mut self) -> Option<char> {
+        if self.ahead.is_empty() {
+            self.ahead.push_back(self.code.next()?);
+        }
+        self.ahead.front().copied()
+    }
+
+    // braces and runs of operator characters are tokens of their own, even without whitespace around them
+    fn scan(&mut self) -> Option<(String, Span)> {
+        let separators = [' ', '\n', '\t', '\r'];
+        // a token not preceded by whitespace or a comment continues the previous one, like the `-` in `1-2`
+        let mut adjacent = self.position.offset > 0;
+        loop {
+            match self.peek_char()? {
+                char if separators.contains(&char) => {
+                    self.next_char();
+                }
+                // this allows not to check for comments in the parsing function
+                '/' if self.starts_with("//") => {
+                    while self.next_char().is_some_and(|char| char != '\n') {}
+                }
+                // block comments nest, so a region containing one can be commented out as a whole
+                '/' if self.starts_with("/*") => {
+                    let start = self.position;
+                    let mut depth = 0;
+                    loop {
+                        if self.starts_with("/*") {
+                            depth += 1;
+                        } else if self.starts_with("*/") {
+                            depth -= 1;
+                        } else if self.next_char().is_none() {
+                            self.unterminated_comment = Some(start);
+                            return None;
+                        } else {
+                            continue;
+                        }
+                        self.next_char();
+                        self.next_char();
+                        if depth == 0 {
+                            break;
+                        }
+                    }
+                }
+                _ => break,
+            }
+            adjacent = false;
+        }
+
+        let start = self.position;
+        let first = self.next_char()?;
+        let mut accumulator = String::from(first);
+        match first {
+            '"' => {
+                while let Some(char) = self.next_char() {
+                    accumulator.push(char);
+                    if char == '"' {
+                        break;
+                    }
+                }
+                return Some((accumulator, start));
+            }
+            '\'' => {
+                // character literals end at the closing quote or, when unterminated, at the end of the line
+                let mut escaped = false;
+                while let Some(char) = self.peek_char().filter(|&char| char != '\n') {
+                    self.next_char();
+                    accumulator.push(char);
+                    match char {
+                        '\\' if !escaped => escaped = true,
+                        '\'' if !escaped => break,
+                        _ => escaped = false,
+                    }
+                }
+                return Some((accumulator, start));
+            }
+            '{' | '}' => return Some((accumulator, start)),
+            // the word operations are the only ones mixing letters and operator characters
+            'w' if ["->", "<-"]
+                .iter()
+                .any(|operator| self.starts_with(operator)) =>
+            {
+                accumulator.extend([self.next_char()?, self.next_char()?]);
+                return Some((accumulator, start));
+            }
+            _ => {}
+        }
+
+        let negative_literal =
+            first == '-' && !adjacent && self.peek_char().is_some_and(|char| char.is_ascii_digit());
+        let operator = Self::is_operator(first) && !negative_literal;
+        while let Some(char) = self.peek_char() {
+            if separators.contains(&char)
+                || matches!(char, '{' | '}' | '"' | '\'')
+                || Self::is_operator(char) != operator
+                || self.at_comment()
+            {
+                break;
+            }
+            self.next_char();
+            accumulator.push(char);
+        }
+        Some((accumulator, start))
+    }
+
+    fn is_operator(char: char) -> bool {
+        "+-*/%&|^~<>=?".contains(char)
+    }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = (String, Span);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.peeked.take() {
+            Some(token) => token,
+            None => self.scan(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Config, HashMap, Io, Machine, MergeError, ParseErrorKind, Program, RunStats, RuntimeError,
+        StepResult, Token, Tokens, FOLD_LIMIT, RECURSION_LIMIT,
+    };
+    #[test]
+    fn test_next_token() {
+        let string = r#"
+        //test
+        fn main {
+            hello
+            "test string"
+        }
+    "#;
+        let code =
+            &mut Tokens::new(string.chars()).map(|(token, span)| (token, span.line, span.column));
+        assert_eq!(code.next(), Some((String::from("fn"), 3, 9)));
+        assert_eq!(code.next(), Some((String::from("main"), 3, 12)));
+        assert_eq!(code.next(), Some((String::from("{"), 3, 17)));
+        assert_eq!(code.next(), Some((String::from("hello"), 4, 13)));
+        assert_eq!(code.next(), Some((String::from("\"test string\""), 5, 13)));
+        assert_eq!(code.next(), Some((String::from("}"), 6, 9)));
+        assert_eq!(code.next(), None);
+
+        let tokens = |code: &str| {
+            Tokens::new(code.chars())
+                .map(|(token, _)| token)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            tokens("fn main{0 putu}"),
+            ["fn", "main", "{", "0", "putu", "}"]
+        );
+        assert_eq!(
+            tokens("fn main{1 putu}}{"),
+            ["fn", "main", "{", "1", "putu", "}", "}", "{"]
+        );
+        assert_eq!(
+            tokens("fn main {\r\n\t1 putu // a\r\n}\r\n"),
+            ["fn", "main", "{", "1", "putu", "}"]
+        );
+        assert_eq!(tokens("1+2*3"), ["1", "+", "2", "*", "3"]);
+        assert_eq!(
+            tokens("1-2 -3 4<<1 x->"),
+            ["1", "-", "2", "-3", "4", "<<", "1", "x", "->"]
+        );
+        assert_eq!(tokens("a w<-b w->???"), ["a", "w<-", "b", "w->", "???"]);
+        assert_eq!(tokens("1//2\n\"a b\"'c'}"), ["1", "\"a b\"", "'c'", "}"]);
+        assert_eq!(tokens("a::b::c{x::y}"), ["a::b::c", "{", "x::y", "}"]);
+        assert_eq!(tokens("1 /* 2 */ 3/**/4"), ["1", "3", "4"]);
+        assert_eq!(tokens("1 /* 2 /* 3 */ 4 */ 5"), ["1", "5"]);
+        assert_eq!(tokens("1 /* /* */ */ */ 2"), ["1", "*/", "2"]);
+        assert_eq!(
+            tokens("fn /* a\n * multi-line\n */ main \"/* b */\" 5/*/ 6 */"),
+            ["fn", "main", "\"/* b */\"", "5"]
+        );
+    }
+
+    macro_rules! test_program_output {
+        ($code: expr, $output: expr) => {{
+            let program = Program::parse($code).unwrap();
+            let mut writer = vec![];
+            let mut io = Io::new(&mut writer);
+            program.interpret(&mut io).unwrap();
+            assert_eq!(writer, $output);
+        }};
+    }
+
+    #[test]
+    fn test_adjacent_braces() {
+        test_program_output!("fn main{1 putu}", "1".as_bytes());
+        test_program_output!(
+            "fn main{1 if{2 putu}else{3 putu}0 while{dup 2<}{1+ \"{}\" drop}putu}",
+            "22".as_bytes()
+        );
+        test_program_output!("fn main {\r\n    4 putu\r\n}\r\n", "4".as_bytes());
+    }
+
+    #[test]
+    fn test_interpreter() {
+        test_program_output!(
+            r#"
+        fn main {
+            69 putu
+            10 putc
+        }
+        "#,
+            "69\n".as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_increment() {
+        test_program_output!(
+            r#"
+        fn main {
+            8 alloc
+            let counter {
+                counter incr putu
+                counter incr putu
+                counter incr putu
+                counter decr putu
+                counter 8 free
+            }
+        }
+        "#,
+            "1232".as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_logic() {
+        test_program_output!(
+            r#"
+        fn main {
+            7 2 let a b {
+                a 3 < b 5 > and if { 1 putu } else { 0 putu }
+                a 3 < b 5 < and if { 1 putu } else { 0 putu }
+                a 3 > b 5 > or if { 1 putu } else { 0 putu }
+                a 3 > b 5 < or if { 1 putu } else { 0 putu }
+                a b = not if { 1 putu } else { 0 putu }
+                a a = not if { 1 putu } else { 0 putu }
+            }
+        }
+        "#,
+            "101010".as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_logic_normalization() {
+        test_program_output!("fn main { 5 0 and putu }", "0".as_bytes());
+        test_program_output!("fn main { 5 3 and putu }", "1".as_bytes());
+        test_program_output!("fn main { 5 3 or putu }", "1".as_bytes());
+        test_program_output!("fn main { 0 0 or putu }", "0".as_bytes());
+        test_program_output!("fn main { 0 not putu }", "1".as_bytes());
+        test_program_output!("fn main { 7 not putu }", "0".as_bytes());
+        // unlike the bitwise operators only the truthiness of the operands matters
+        test_program_output!("fn main { 2 1 & putu }", "0".as_bytes());
+        test_program_output!("fn main { 2 1 and putu }", "1".as_bytes());
+    }
+
+    #[test]
+    fn test_histogram() {
+        test_program_output!(
+            r#"
+        fn main {
+            2048 alloc
+            let counts {
+                "aab" 3 counts histogram
+                counts 776 + <- putu // 'a' * 8
+                counts 784 + <- putu // 'b' * 8
+                counts 792 + <- putu // 'c' * 8
+            }
+        }
+        "#,
+            "210".as_bytes()
+        );
+
+        let program = Program::parse(r#"fn main { 16 alloc "aab" 3 rot histogram }"#).unwrap();
+        assert_eq!(
+            program.interpret(&mut Io::new(vec![])),
+            Err(RuntimeError::OutOfBounds(0))
+        );
+    }
+
+    #[test]
+    fn test_crlf_to_lf() {
+        test_program_output!(
+            r#"
+        fn main {
+            6 alloc
+            let buffer {
+                // "a\r\nb\r\n"
+                buffer 97 ->
+                buffer 1 + 13 ->
+                buffer 2 + 10 ->
+                buffer 3 + 98 ->
+                buffer 4 + 13 ->
+                buffer 5 + 10 ->
+                buffer 6 crlf2lf putu
+                buffer <- putu
+                buffer 1 + <- putu
+                buffer 2 + <- putu
+                buffer 3 + <- putu
+            }
+        }
+        "#,
+            "497109810".as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_here() {
+        test_program_output!("fn main { here 4 alloc = putu }", "1".as_bytes());
+        test_program_output!(
+            r#"
+        fn main {
+            4 alloc 4 alloc
+            here putu
+            drop 4 free
+            here putu
+            2 alloc putu
+        }
+        "#,
+            "800".as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_debug_sink() {
+        let program = Program::parse(
+            r#"
+        fn main {
+            1 2 ???
+            putu
+        }
+        "#,
+        )
+        .unwrap();
+        let mut writer = vec![];
+        let mut debug = vec![];
+        let mut config = Config {
+            debug: Some(&mut debug),
+            ..Default::default()
+        };
+        program
+            .interpret_with_config(&mut Io::new(&mut writer), &mut config)
+            .unwrap();
+        assert_eq!(writer, "2".as_bytes());
+        assert!(String::from_utf8(debug).unwrap().starts_with("[1, 2] "));
+    }
+
+    #[test]
+    fn test_entry_point() {
+        let program = Program::parse(
+            r#"
+        fn start {
+            42 putu
+        }
+        "#,
+        )
+        .unwrap();
+        let mut writer = vec![];
+        assert_eq!(
+            program.interpret(&mut Io::new(&mut writer)),
+            Err(RuntimeError::MissingEntryPoint(String::from("main")))
+        );
+        let mut config = Config {
+            entry_point: Some("start"),
+            ..Default::default()
+        };
+        program
+            .interpret_with_config(&mut Io::new(&mut writer), &mut config)
+            .unwrap();
+        assert_eq!(writer, "42".as_bytes());
+    }
+
+    #[test]
+    fn test_bitwise() {
+        test_program_output!("fn main { 12 10 & putu }", "8".as_bytes());
+        test_program_output!("fn main { 12 10 | putu }", "14".as_bytes());
+        test_program_output!("fn main { 12 10 ^ putu }", "6".as_bytes());
+        test_program_output!("fn main { 1 4 << putu }", "16".as_bytes());
+        test_program_output!("fn main { 16 3 >> putu }", "2".as_bytes());
+        test_program_output!("fn main { 0 ~ 60 >> putu }", "15".as_bytes());
+
+        let program = Program::parse("fn main { 1 64 << }").unwrap();
+        assert_eq!(
+            program.interpret(&mut Io::new(vec![])),
+            Err(RuntimeError::ShiftOverflow(64))
+        );
+    }
+
+    #[test]
+    fn test_function_names() {
+        let mut program = Program::parse("fn main { } fn foo { } fn bar { foo }").unwrap();
+        assert_eq!(program.function_names(), ["bar", "foo", "main"]);
+        program.prune_unreachable(&["main"]);
+        assert_eq!(program.function_names(), ["main"]);
+        assert!(Program::parse("").unwrap().function_names().is_empty());
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut program = Program::parse("fn main { helper } fn helper { } fn unused { }").unwrap();
+        assert_eq!(program.validate(), Ok(()));
+        program.prune_unreachable(&["unused"]);
+        assert_eq!(program.validate(), Err(ParseErrorKind::MissingMain));
+        assert_eq!(
+            Program::parse("fn helper { }").unwrap().validate(),
+            Err(ParseErrorKind::MissingMain)
+        );
+
+        // a call left to a function that was never defined, which parsing rejects
+        let mut program = Program::parse("fn main { }").unwrap();
+        let helper = program.functions.reserve("helper");
+        let main = program.functions.index("main").unwrap();
+        program.functions.get_mut(main).unwrap().body = vec![Token::FunctionCall(helper)];
+        assert_eq!(
+            program.validate(),
+            Err(ParseErrorKind::UndefinedFunction(String::from("helper")))
+        );
+    }
+
+    #[test]
+    fn test_call_graph() {
+        let program = Program::parse(
+            r#"
+        fn square { dup * }
+        fn cube { dup square * }
+        fn print { putu }
+        fn main {
+            3 cube print
+            2 while { dup 0 > } {
+                1 - dup square print
+            }
+            let x { x square print }
+        }
+        "#,
+        )
+        .unwrap();
+        let expected = HashMap::from([
+            (String::from("square"), vec![]),
+            (String::from("cube"), vec![String::from("square")]),
+            (String::from("print"), vec![]),
+            (
+                String::from("main"),
+                vec![
+                    String::from("cube"),
+                    String::from("print"),
+                    String::from("square"),
+                ],
+            ),
+        ]);
+        assert_eq!(program.call_graph(), expected);
+    }
+
+    #[test]
+    fn test_prune_unreachable() {
+        let mut program = Program::parse(
+            r#"
+        fn helper { 1 + }
+        fn unused { helper }
+        fn print { putu }
+        fn main { 41 helper print }
+        "#,
+        )
+        .unwrap();
+        assert_eq!(
+            program.unreachable_functions(&["main"]),
+            vec![String::from("unused")]
+        );
+        assert_eq!(
+            program.unreachable_functions(&["print"]),
+            vec![
+                String::from("helper"),
+                String::from("main"),
+                String::from("unused")
+            ]
+        );
+        assert_eq!(
+            program.prune_unreachable(&["main"]),
+            vec![String::from("unused")]
+        );
+        assert!(program.unreachable_functions(&["main"]).is_empty());
+        let mut writer = vec![];
+        program.interpret(&mut Io::new(&mut writer)).unwrap();
+        assert_eq!(writer, "42".as_bytes());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            Program::parse("fn main { foo }").unwrap_err().kind,
+            ParseErrorKind::UndefinedFunction(String::from("foo"))
+        );
+        assert_eq!(
+            Program::parse("main { }").unwrap_err().kind,
+            ParseErrorKind::UnexpectedToken {
+                found: String::from("main"),
+                expected: "fn`, `mod`, `global`, `const` or `include"
+            }
+        );
+        assert_eq!(
+            Program::parse("fn main { 1 if 2 }").unwrap_err().kind,
+            ParseErrorKind::UnexpectedToken {
+                found: String::from("2"),
+                expected: "{"
+            }
+        );
+        assert_eq!(
+            Program::parse("fn main { while").unwrap_err().kind,
+            ParseErrorKind::UnexpectedEof
+        );
+        assert_eq!(
+            Program::parse("fn main { let a b").unwrap_err().kind,
+            ParseErrorKind::UnexpectedEof
+        );
+        let error = Program::parse("fn main { 1 putu }\n/* fn unused { 2 putu }").unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::UnterminatedComment);
+        assert_eq!((error.span.line, error.span.column), (2, 1));
+        assert_eq!(
+            Program::parse("fn main { let a /* b } }").unwrap_err().kind,
+            ParseErrorKind::UnterminatedComment
+        );
+        assert!(Program::parse("fn main { /* 1 /* 2 */ } */ }").is_ok());
+        assert_eq!(
+            Program::parse("fn main { 1 if { 2 } putu")
+                .unwrap_err()
+                .kind,
+            ParseErrorKind::UnexpectedEof
+        );
+        assert_eq!(
+            Program::parse("fn main { } /* 1 /* 2 */").unwrap_err().kind,
+            ParseErrorKind::UnterminatedComment
+        );
+        for literal in [
+            "99999999999999999999999999",
+            "-99999999999999999999999999",
+            "0x1FFFFFFFFFFFFFFFF",
+        ] {
+            assert_eq!(
+                Program::parse(&format!("fn main {{ {literal} }}"))
+                    .unwrap_err()
+                    .kind,
+                ParseErrorKind::IntegerLiteralOverflow(String::from(literal))
+            );
+        }
+        assert_eq!(
+            Program::parse("global buffer 99999999999999999999")
+                .unwrap_err()
+                .kind,
+            ParseErrorKind::IntegerLiteralOverflow(String::from("99999999999999999999"))
+        );
+    }
+
+    #[test]
+    fn test_signed() {
+        test_program_output!("fn main { 3 5 - puti }", "-2".as_bytes());
+        test_program_output!("fn main { -4 puti }", "-4".as_bytes());
+        test_program_output!("fn main { -4 3 * puti }", "-12".as_bytes());
+        test_program_output!("fn main { -4 6 + puti }", "2".as_bytes());
+        test_program_output!("fn main { 0 1 - putu }", usize::MAX.to_string().as_bytes());
+        test_program_output!(
+            &format!("fn main {{ {} putu }}", usize::MAX),
+            usize::MAX.to_string().as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_radix_literals() {
+        test_program_output!("fn main { 0xFF putu }", "255".as_bytes());
+        test_program_output!("fn main { 0xff putu }", "255".as_bytes());
+        test_program_output!("fn main { 0b1010 putu }", "10".as_bytes());
+        test_program_output!("fn main { 0o17 putu }", "15".as_bytes());
+        test_program_output!("fn main { 0XfF putu 0B11 putu }", "2553".as_bytes());
+        for literal in ["0xG1", "0xZZ", "0B2"] {
+            assert_eq!(
+                Program::parse(&format!("fn main {{ {literal} }}"))
+                    .unwrap_err()
+                    .kind,
+                ParseErrorKind::InvalidLiteral(String::from(literal))
+            );
+        }
+        assert_eq!(
+            Program::parse("fn main { 0b }").unwrap_err().kind,
+            ParseErrorKind::InvalidLiteral(String::from("0b"))
+        );
+    }
+
+    #[test]
+    fn test_digit_separators() {
+        test_program_output!("fn main { 1_000 putu }", "1000".as_bytes());
+        test_program_output!(
+            "fn main { 1_000_000 putu 0xFF_FF putu 0b1_0 putu -1_0 10 + putu }",
+            "10000006553520".as_bytes()
+        );
+        test_program_output!(
+            "fn main { 1_0 match { case 1_0 { 1 putu } else { 0 putu } } }",
+            "1".as_bytes()
+        );
+        for literal in ["_5", "5_", "1__0", "0x_FF"] {
+            assert_eq!(
+                Program::parse(&format!("fn main {{ {literal} }}"))
+                    .unwrap_err()
+                    .kind,
+                ParseErrorKind::InvalidLiteral(String::from(literal))
+            );
+        }
+        assert_eq!(
+            Program::parse("fn main { _tmp }").unwrap_err().kind,
+            ParseErrorKind::UndefinedFunction(String::from("_tmp"))
+        );
+    }
+
+    #[test]
+    fn test_parse_chars() {
+        let code = r#"
+        /* squares /* nested */ */
+        fn square (n) -> (s) { n n * }
+        fn main { 0 5 for { 1 + dup square putu } drop "done" drop }
+        "#;
+        let chunks: Vec<String> = code
+            .chars()
+            .collect::<Vec<_>>()
+            .chunks(3)
+            .map(|chunk| chunk.iter().collect())
+            .collect();
+        let program = Program::parse_chars(chunks.iter().flat_map(|chunk| chunk.chars())).unwrap();
+        assert_eq!(
+            program.disassemble(),
+            Program::parse(code).unwrap().disassemble()
+        );
+        let mut writer = vec![];
+        program.interpret(&mut Io::new(&mut writer)).unwrap();
+        assert_eq!(writer, "1491625".as_bytes());
+        assert_eq!(
+            Program::parse_chars("fn main { /* 1".chars())
+                .unwrap_err()
+                .kind,
+            ParseErrorKind::UnterminatedComment
+        );
+    }
+
+    #[test]
+    fn test_parse_error_span() {
+        let error = Program::parse(
+            r#"
+        fn square { dup * }
+
+        fn main {
+            3 square
+            square foo putu
+        }
+        "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.kind,
+            ParseErrorKind::UndefinedFunction(String::from("foo"))
+        );
+        assert_eq!((error.span.line, error.span.column), (6, 20));
+        assert_eq!(
+            error.to_string(),
+            "at line 6, col 20: call to undefined function `foo`"
+        );
+        let error = Program::parse("fn main { foo }").unwrap_err();
+        assert_eq!((error.span.line, error.span.column), (1, 11));
+        assert_eq!(
+            format!("error {error}"),
+            "error at line 1, col 11: call to undefined function `foo`"
+        );
+        assert_eq!(
+            Program::parse("fn main {\n  while").unwrap_err().span.line,
+            2
+        );
+    }
+
+    #[test]
+    fn test_input() {
+        let program =
+            Program::parse("fn main { getc putu getc putc getu getu + putu getu putu }").unwrap();
+        let mut writer = vec![];
+        let mut io = Io::with_reader(&mut writer, "AB  12\n 30".as_bytes());
+        program.interpret(&mut io).unwrap();
+        assert_eq!(writer, format!("65B42{}", usize::MAX).as_bytes());
+
+        let program = Program::parse(
+            r#"
+        fn main {
+            readline let len address {
+                len putu address <- putc address 1 + <- putc address 2 + <- putu
+            }
+            readline putu drop readline putu <- putc readline putu drop
+        }
+        "#,
+        )
+        .unwrap();
+        let mut writer = vec![];
+        let mut io = Io::with_reader(&mut writer, "hi\n\nend".as_bytes());
+        program.interpret(&mut io).unwrap();
+        assert_eq!(writer, "3hi1013e0".as_bytes());
+
+        let program = Program::parse("fn main { getc getc + putu }").unwrap();
+        let mut writer = vec![];
+        let mut io = Io::with_reader(&mut writer, [1, 2].as_slice());
+        program.interpret(&mut io).unwrap();
+        assert_eq!(writer, "3".as_bytes());
+
+        let program = Program::parse("fn main { getc putu }").unwrap();
+        let mut writer = vec![];
+        program.interpret(&mut Io::new(&mut writer)).unwrap();
+        assert_eq!(writer, usize::MAX.to_string().as_bytes());
+    }
+
+    #[test]
+    fn test_getenv() {
+        let program = Program::parse(
+            r#"
+        fn main {
+            "HOME" getenv putu let len address {
+                len putu address <- putc address len + 1 - <- putc
+            }
+            "MISSING" getenv putu putu putu
+        }
+        "#,
+        )
+        .unwrap();
+        let env = |name: &str| (name == "HOME").then(|| String::from("/home/user"));
+        let mut config = Config {
+            env: Some(&env),
+            ..Default::default()
+        };
+        let mut writer = vec![];
+        program
+            .interpret_with_config(&mut Io::new(&mut writer), &mut config)
+            .unwrap();
+        assert_eq!(writer, "110/r000".as_bytes());
+    }
+
+    #[test]
+    fn test_globals() {
+        test_program_output!(
+            r#"
+        global counter 8
+        global buffer 4
+        fn bump { counter incr drop }
+        fn main {
+            counter putu buffer putu
+            buffer buffer = putu
+            buffer <- putu buffer 3 + <- putu
+            bump bump counter <- putu
+            buffer 7 ->
+            4 alloc putu
+            buffer <- putu
+        }
+        "#,
+            "081002127".as_bytes()
+        );
+        assert_eq!(
+            Program::parse("global a 1 global a 2").unwrap_err().kind,
+            ParseErrorKind::DuplicateGlobal(String::from("a"))
+        );
+        assert_eq!(
+            Program::parse("global a b").unwrap_err().kind,
+            ParseErrorKind::InvalidLiteral(String::from("b"))
+        );
+    }
+
+    #[test]
+    fn test_modules() {
+        let source = r#"
+        mod a {
+            fn helper { 'a' putc }
+            fn run { helper }
+        }
+        mod b {
+            fn run { helper }
+            fn helper { 'b' putc }
+        }
+        fn helper { 'c' putc }
+        fn main { a::run b::run helper b::helper }
+        "#;
+        test_program_output!(source, "abcb".as_bytes());
+        assert_eq!(
+            Program::parse(source).unwrap().function_names(),
+            [
+                "a::helper",
+                "a::run",
+                "b::helper",
+                "b::run",
+                "helper",
+                "main"
+            ]
+        );
+        // modules without the function call the one of the closest module around them
+        test_program_output!(
+            r#"
+        fn print (n) { n putu }
+        mod outer {
+            fn helper { 1 print }
+            mod inner { fn run (n) { n print helper } }
+            fn run { 2 outer::inner::run }
+        }
+        fn main { outer::run 3 outer::inner::run }
+        "#,
+            "2131".as_bytes()
+        );
+
+        assert_eq!(
+            Program::parse("mod a { fn run { missing } } fn main { }")
+                .unwrap_err()
+                .kind,
+            ParseErrorKind::UndefinedFunction(String::from("missing"))
+        );
+        assert_eq!(
+            Program::parse("mod a { fn helper { } } fn main { b::helper }")
+                .unwrap_err()
+                .kind,
+            ParseErrorKind::UndefinedFunction(String::from("b::helper"))
+        );
+        assert_eq!(
+            Program::parse("mod a { fn helper { } } fn main { helper }")
+                .unwrap_err()
+                .kind,
+            ParseErrorKind::UndefinedFunction(String::from("helper"))
+        );
+        assert_eq!(
+            Program::parse("mod a { fn helper { }").unwrap_err().kind,
+            ParseErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn test_forward_calls() {
+        test_program_output!(
+            r#"
+        fn main { 7 is_even putu 7 is_odd putu 10 is_even putu double putu }
+        fn is_even (n) -> (even) { n 0 = if { 1 } else { n 1 - is_odd } }
+        fn is_odd (n) -> (odd) { n 0 = if { 0 } else { n 1 - is_even } }
+        fn double pure { 21 2 * }
+        "#,
+            "01142".as_bytes()
+        );
+        // pure and result checks see the functions defined after the one they check
+        for (code, error) in [
+            (
+                "fn main pure { later } fn later { 1 putu }",
+                ParseErrorKind::ImpureFunction {
+                    function: String::from("main"),
+                    token: String::from("later"),
+                },
+            ),
+            (
+                "fn main -> (a) { later } fn later { 1 2 }",
+                ParseErrorKind::ResultCount {
+                    function: String::from("main"),
+                    expected: 1,
+                    found: 2,
+                },
+            ),
+            (
+                "fn main { later } fn other { }",
+                ParseErrorKind::UndefinedFunction(String::from("later")),
+            ),
+            (
+                "fn main { @ }",
+                ParseErrorKind::UnknownToken(String::from("@")),
+            ),
+        ] {
+            assert_eq!(Program::parse(code).unwrap_err().kind, error);
+        }
+    }
+
+    #[test]
+    fn test_duplicate_functions() {
+        let error = Program::parse("fn foo { 1 }\nfn bar { }\nfn foo { 2 }").unwrap_err();
+        assert_eq!(
+            error.kind,
+            ParseErrorKind::DuplicateFunction(String::from("foo"))
+        );
+        assert_eq!((error.span.line, error.span.column), (3, 4));
+    }
+
+    #[test]
+    fn test_constants() {
+        test_program_output!(
+            r#"
+        const WIDTH 80
+        const SEPARATOR ','
+        const MASK 0xF_F
+        const OFFSET -1
+        global line 80
+        fn main {
+            WIDTH putu SEPARATOR putc MASK putu
+            WIDTH OFFSET + putu
+            WIDTH alloc WIDTH free
+        }
+        "#,
+            "80,25579".as_bytes()
+        );
+        for (code, error) in [
+            (
+                "const A 1 const A 2",
+                ParseErrorKind::DuplicateConstant(String::from("A")),
+            ),
+            (
+                "global a 1 const a 2",
+                ParseErrorKind::DuplicateConstant(String::from("a")),
+            ),
+            (
+                "const a 1 global a 2",
+                ParseErrorKind::DuplicateGlobal(String::from("a")),
+            ),
+            (
+                "const A B",
+                ParseErrorKind::InvalidLiteral(String::from("B")),
+            ),
+            (
+                "fn main { A putu } const A 1",
+                ParseErrorKind::UndefinedFunction(String::from("A")),
+            ),
+        ] {
+            assert_eq!(Program::parse(code).unwrap_err().kind, error);
+        }
+    }
+
+    #[test]
+    fn test_include() {
+        let directory =
+            std::env::temp_dir().join(format!("stack_lang_include_{}", std::process::id()));
+        std::fs::create_dir_all(directory.join("lib")).unwrap();
+        let write =
+            |name: &str, source: &str| std::fs::write(directory.join(name), source).unwrap();
+        write("lib/square.rsl", "const SIDE 7 fn square { dup * }");
+        write(
+            "lib/cube.rsl",
+            "include \"square.rsl\" fn cube { dup square * }",
+        );
+        write(
+            "main.rsl",
+            "include \"lib/square.rsl\" include \"lib/cube.rsl\" fn main { SIDE square putu 2 cube putu }",
+        );
+        let program = Program::parse_file(directory.join("main.rsl")).unwrap();
+        let mut writer = vec![];
+        program.interpret(&mut Io::new(&mut writer)).unwrap();
+        assert_eq!(writer, "498".as_bytes());
+
+        write("a.rsl", "include \"b.rsl\" fn a { }");
+        write("b.rsl", "fn b { }\ninclude \"a.rsl\"");
+        let error = Program::parse_file(directory.join("a.rsl")).unwrap_err();
+        assert_eq!((error.span.line, error.span.column), (1, 9));
+        match error.kind {
+            ParseErrorKind::Included { path, error } => {
+                assert!(path.ends_with("b.rsl"));
+                assert_eq!((error.span.line, error.span.column), (2, 9));
+                assert!(
+                    matches!(error.kind, ParseErrorKind::CircularInclude(path) if path.ends_with("a.rsl"))
+                );
+            }
+            kind => panic!("unexpected error {kind}"),
+        }
+
+        write("missing.rsl", "fn main { }\ninclude \"nowhere.rsl\"");
+        let error = Program::parse_file(directory.join("missing.rsl")).unwrap_err();
+        assert!(
+            matches!(error.kind, ParseErrorKind::IncludeFailed(path) if path.ends_with("nowhere.rsl"))
+        );
+        assert!(matches!(
+            Program::parse_file(directory.join("none.rsl"))
+                .unwrap_err()
+                .kind,
+            ParseErrorKind::IncludeFailed(_)
+        ));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_char_classification() {
+        test_program_output!(
+            "fn main { 48 isdigit putu 57 isdigit putu 65 isdigit putu 304 isdigit putu }",
+            "1100".as_bytes()
+        );
+        test_program_output!(
+            "fn main { 65 isalpha putu 122 isalpha putu 48 isalpha putu 91 isalpha putu }",
+            "1100".as_bytes()
+        );
+        test_program_output!(
+            "fn main { 32 isspace putu 10 isspace putu 9 isspace putu 95 isspace putu }",
+            "1110".as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_char_literals() {
+        test_program_output!("fn main { 'A' putu 'A' putc }", "65A".as_bytes());
+        test_program_output!(r"fn main { '\n' putc '\r' putu }", "\n13".as_bytes());
+        test_program_output!(
+            r"fn main { ' ' putu '\t' putu '\0' putu '\\' putc '\'' putc }",
+            r"3290\'".as_bytes()
+        );
+        for literal in ["'ab'", "'a", "''", r"'\q'"] {
+            assert_eq!(
+                Program::parse(&format!("fn main {{ {literal}\n}}"))
+                    .unwrap_err()
+                    .kind,
+                ParseErrorKind::InvalidLiteral(literal.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_char_case() {
+        test_program_output!(
+            "fn main { 'a' toupper putc 'Z' toupper putc '1' toupper putc '{' toupper putc }",
+            "AZ1{".as_bytes()
+        );
+        test_program_output!(
+            "fn main { 'A' tolower putc 'z' tolower putc '@' tolower putc 321 tolower putu }",
+            "az@321".as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_leak_check() {
+        let program = Program::parse(
+            r#"
+        global buffer 16
+        fn main { 4 alloc drop "leak" drop 2 alloc 2 free }
+        "#,
+        )
+        .unwrap();
+        program.interpret(&mut Io::new(vec![])).unwrap();
+        let mut config = Config {
+            check_leaks: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            program.interpret_with_config(&mut Io::new(vec![]), &mut config),
+            Err(RuntimeError::MemoryLeak { leaked_bytes: 9 })
+        );
+
+        let program = Program::parse(
+            r#"
+        global buffer 16
+        fn main { 4 alloc 4 free "freed" 6 free }
+        "#,
+        )
+        .unwrap();
+        program
+            .interpret_with_config(&mut Io::new(vec![]), &mut config)
+            .unwrap();
+
+        // freeing a global is not a leak, whether or not leaks are checked
+        test_program_output!("global g 8 fn main { g 8 free 1 putu }", "1".as_bytes());
+        let program = Program::parse("global g 8 fn main { g 8 free }").unwrap();
+        program
+            .interpret_with_config(&mut Io::new(vec![]), &mut config)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_invalid_free() {
+        let interpret = |code: &str| {
+            Program::parse(code)
+                .unwrap()
+                .interpret(&mut Io::new(vec![]))
+        };
+        assert_eq!(
+            interpret("fn main { 100 5 free }"),
+            Err(RuntimeError::OutOfBounds(100))
+        );
+        assert_eq!(
+            interpret("fn main { 4 alloc drop 0 1 - 5 free }"),
+            Err(RuntimeError::OutOfBounds(usize::MAX))
+        );
+        assert_eq!(
+            interpret("fn main { 4 alloc 4 free 0 4 free 1 putu }"),
+            Err(RuntimeError::InvalidFree(0))
+        );
+        // freeing a part of a free range is rejected too
+        assert_eq!(
+            interpret("fn main { 8 alloc 4 + 4 free 2 6 free }"),
+            Err(RuntimeError::InvalidFree(2))
+        );
+        assert_eq!(
+            interpret("fn main { 8 alloc 8 free 8 alloc 8 free }"),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_stack_underflow() {
+        let cases = [
+            ("fn main { + }", "+"),
+            ("fn main { 1 < }", "<"),
+            ("fn main { 1 2 3 rot rot drop drop drop drop }", "drop"),
+            ("fn main { 1 over }", "over"),
+            ("fn main { dup }", "dup"),
+            ("fn main { <- }", "<-"),
+            ("fn main { if { 2 } }", "if"),
+            ("fn main { 1 let a b { } }", "let"),
+        ];
+        for (code, token) in cases {
+            let program = Program::parse(code).unwrap();
+            let error = program.interpret(&mut Io::new(vec![])).unwrap_err();
+            assert_eq!(error, RuntimeError::StackUnderflow(token.to_string()));
+        }
+        let program = Program::parse("fn main { + }").unwrap();
+        assert_eq!(
+            program
+                .interpret(&mut Io::new(vec![]))
+                .unwrap_err()
+                .to_string(),
+            "stack underflow in `+`"
+        );
+    }
+
+    #[test]
+    fn test_frames() {
+        test_program_output!(
+            "fn main { 1 2 3 2 pushframe + putu popframe putu }",
+            "51".as_bytes()
+        );
+        let program = Program::parse("fn main { 1 2 3 2 pushframe drop drop drop }").unwrap();
+        assert_eq!(
+            program.interpret(&mut Io::new(vec![])),
+            Err(RuntimeError::StackUnderflow("drop".to_string()))
+        );
+        let program = Program::parse("fn main { 1 2 pushframe }").unwrap();
+        assert_eq!(
+            program.interpret(&mut Io::new(vec![])),
+            Err(RuntimeError::StackUnderflow("pushframe".to_string()))
+        );
+        let program = Program::parse("fn main { popframe }").unwrap();
+        assert_eq!(
+            program.interpret(&mut Io::new(vec![])),
+            Err(RuntimeError::UnbalancedFrame)
+        );
+    }
+
+    #[test]
+    fn test_stack_effects() {
+        let program = Program::parse(
+            r#"
+        fn add { + }
+        fn sub (a b) { a b - }
+        fn pair -> (a b) { 1 2 }
+        fn countdown (n) { n if { n 1 - countdown } }
+        fn sometimes { if { 1 } }
+        fn main { 1 2 add 3 sub drop pair 2drop 5 countdown }
+        "#,
+        )
+        .unwrap();
+        let effects = program.stack_effects();
+        assert_eq!(effects["add"], Some(-1));
+        assert_eq!(effects["sub"], Some(-1));
+        assert_eq!(effects["pair"], Some(2));
+        assert_eq!(effects["countdown"], None);
+        assert_eq!(effects["sometimes"], None);
+        assert_eq!(effects["main"], None);
+        assert_eq!(effects.len(), 6);
+    }
+
+    #[test]
+    fn test_depth() {
+        test_program_output!("fn main { depth putu 1 2 3 depth putu }", "03".as_bytes());
+        test_program_output!(
+            "fn main { 1 2 3 2 pushframe depth putu popframe depth putu }",
+            "23".as_bytes()
+        );
+        // calls of pure functions on literals are not folded when the function looks at the whole stack
+        test_program_output!(
+            "fn count pure { depth } fn main { 7 1 2 count putu }",
+            "3".as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_puts() {
+        test_program_output!(
+            r#"fn main { "hello" puts 32 putc "multiline" 5 + puts }"#,
+            "hello line".as_bytes()
+        );
+        // the bytes between the quotes are written as they are
+        test_program_output!(r#"fn main { "a\qb\n" puts }"#, r"a\qb\n".as_bytes());
+        let program = Program::parse("fn main { 1 alloc dup 'a' -> puts }").unwrap();
+        assert_eq!(
+            program.interpret(&mut Io::new(vec![])),
+            Err(RuntimeError::OutOfBounds(0))
+        );
+    }
+
+    #[test]
+    fn test_seed() {
+        let program = Program::parse(
+            "fn main { getseed let start { rand putu 32 putc start seed rand putu } 5 seed rand }",
+        )
+        .unwrap();
+        let mut writer = vec![];
+        let machine = program.run(&mut Io::new(&mut writer)).unwrap();
+        let output = String::from_utf8(writer).unwrap();
+        let (first, second) = output.split_once(' ').unwrap();
+        assert_eq!(first, second);
+
+        assert_eq!(machine.seed(), 5 + 0x9E3779B97F4A7C15);
+
+        let program = Program::parse("fn main { rand rand }").unwrap();
+        let run = |seed| {
+            let mut config = Config {
+                seed: Some(seed),
+                ..Default::default()
+            };
+            let machine = program.run_with_config(&mut Io::new(vec![]), &mut config);
+            machine.unwrap().stack().to_vec()
+        };
+        assert_eq!(run(1), run(1));
+        assert_ne!(run(1), run(2));
+    }
+
+    #[test]
+    fn test_stack_words() {
+        for (code, stack) in [
+            ("1 2 3 nip", vec![1, 3]),
+            ("1 2 3 tuck", vec![1, 3, 2, 3]),
+            ("1 2 3 2dup", vec![1, 2, 3, 2, 3]),
+            ("1 2 nip", vec![2]),
+            ("1 2 tuck", vec![2, 1, 2]),
+            ("1 2 2dup", vec![1, 2, 1, 2]),
+            ("1 2 2drop", vec![]),
+            ("1 2 3 0 pick", vec![1, 2, 3, 3]),
+            ("1 2 3 2 pick", vec![1, 2, 3, 1]),
+            ("1 2 3 2 pushframe 1 pick", vec![1, 2, 3, 2]),
+        ] {
+            let program = Program::parse(&format!("fn main {{ {code} }}")).unwrap();
+            let machine = program.run(&mut Io::new(vec![])).unwrap();
+            assert_eq!(machine.stack(), stack);
+        }
+        for word in ["nip", "tuck", "2dup", "2drop", "pick"] {
+            let program = Program::parse(&format!("fn main {{ 1 {word} }}")).unwrap();
+            assert_eq!(
+                program.interpret(&mut Io::new(vec![])),
+                Err(RuntimeError::StackUnderflow(word.to_string()))
+            );
+        }
+        let program = Program::parse("fn main { 1 2 1 pick 3 pick }").unwrap();
+        assert_eq!(
+            program.interpret(&mut Io::new(vec![])),
+            Err(RuntimeError::StackUnderflow(String::from("pick")))
+        );
+    }
+
+    #[test]
+    fn test_byte_stack() {
+        test_program_output!(
+            r#"
+        global bytes 3
+        fn main {
+            bytes 3 sinit
+            'a' spush 'b' spush spop putc 'c' spush 'd' spush
+            spop putc spop putc spop putc
+        }
+        "#,
+            "bdca".as_bytes()
+        );
+        for (code, error) in [
+            (
+                "bytes 2 sinit 1 spush 2 spush 3 spush",
+                RuntimeError::ByteStackOverflow(2),
+            ),
+            (
+                "bytes 2 sinit 1 spush spop spop",
+                RuntimeError::ByteStackUnderflow,
+            ),
+            ("1 spush", RuntimeError::ByteStackOverflow(0)),
+            (
+                "bytes 1 + 2 sinit 1 spush 2 spush",
+                RuntimeError::OutOfBounds(2),
+            ),
+        ] {
+            let program = Program::parse(&format!("global bytes 2 fn main {{ {code} }}")).unwrap();
+            assert_eq!(program.interpret(&mut Io::new(vec![])), Err(error));
+        }
+    }
+
+    #[test]
+    fn test_allocation_limit() {
+        let run = |code| {
+            let program = Program::parse(code).unwrap();
+            let mut config = Config {
+                allocation_limit: Some(2),
+                ..Default::default()
+            };
+            program.interpret_with_config(&mut Io::new(vec![]), &mut config)
+        };
+        assert_eq!(
+            run("fn main { 1 alloc \"a\" 1 alloc }"),
+            Err(RuntimeError::AllocationLimit(2))
+        );
+        assert_eq!(
+            run("global table 64 fn main { 1 alloc \"a\" 2 free 1 alloc }"),
+            Ok(())
+        );
+        // freeing nothing or only a part of an allocation doesn't make room for another one
+        assert_eq!(
+            run("fn main { 2 alloc 1 alloc 0 0 free 0 0 free 0 1 free 1 alloc }"),
+            Err(RuntimeError::AllocationLimit(2))
+        );
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let run = |code| {
+            let program = Program::parse(code).unwrap();
+            let mut config = Config {
+                memory_limit: Some(16),
+                ..Default::default()
+            };
+            program.interpret_with_config(&mut Io::new(vec![]), &mut config)
+        };
+        assert_eq!(
+            run("fn main { 8 alloc 8 alloc 1 alloc }"),
+            Err(RuntimeError::OutOfMemory(1))
+        );
+        assert_eq!(
+            run("fn main { 12 alloc drop \"hello\" }"),
+            Err(RuntimeError::OutOfMemory(6))
+        );
+        assert_eq!(
+            run("global table 20 fn main { }"),
+            Err(RuntimeError::OutOfMemory(20))
+        );
+        assert_eq!(run("fn main { 16 alloc 16 free 16 alloc drop }"), Ok(()));
+        // eight bytes are free, but in two blocks of four
+        assert_eq!(
+            run("fn main { 4 alloc 4 alloc 4 alloc 4 alloc drop 4 free drop 4 free 6 alloc }"),
+            Err(RuntimeError::OutOfMemory(6))
+        );
+    }
+
+    #[test]
+    fn test_counted_memory() {
+        let program = Program::parse(
+            r#"
+        fn main {
+            8 ralloc let handle {
+                handle incref
+                handle decref
+                handle raddr 7 w->
+                handle raddr w<- putu
+                handle raddr
+                handle decref
+            }
+            8 alloc = putu
+        }
+        "#,
+        )
+        .unwrap();
+        let mut writer = vec![];
+        let mut config = Config {
+            check_leaks: true,
+            ..Default::default()
+        };
+        let result = program.interpret_with_config(&mut Io::new(&mut writer), &mut config);
+        assert_eq!(result, Err(RuntimeError::MemoryLeak { leaked_bytes: 8 }));
+        assert_eq!(writer, "71".as_bytes());
+
+        let program = Program::parse("fn main { 1 ralloc dup decref raddr }").unwrap();
+        assert_eq!(
+            program.interpret(&mut Io::new(vec![])),
+            Err(RuntimeError::InvalidHandle(0))
+        );
+    }
+
+    #[test]
+    fn test_function_parameters() {
+        test_program_output!(
+            r#"
+        fn add (a b) { a b + }
+        fn sub ( a b ) { a b - }
+        fn main { 2 3 add putu 10 4 sub putu }
+        "#,
+            "56".as_bytes()
+        );
+        // callers can't leak their bindings into the function
+        let program = Program::parse("fn main { 1 let a { a show } } fn show { a putu }");
+        assert!(program.is_err());
+        let program = Program::parse("fn add (a b) { a b + } fn main { 1 add }").unwrap();
+        assert_eq!(
+            program.interpret(&mut Io::new(vec![])),
+            Err(RuntimeError::StackUnderflow("add".to_string()))
+        );
+        assert!(Program::parse("fn add (a (b)) { }").is_err());
+        assert!(Program::parse("fn add (a b { }").is_err());
+    }
+
+    #[test]
+    fn test_stats() {
+        let program = Program::parse(
+            r#"
+        fn square { dup * }
+        fn unused { }
+        fn main { 2 square 3 square + putu }
+        "#,
+        )
+        .unwrap();
+        let mut stats = RunStats::default();
+        let mut config = Config {
+            stats: Some(&mut stats),
+            ..Default::default()
+        };
+        let mut writer = Vec::new();
+        program
+            .interpret_with_config(&mut Io::new(&mut writer), &mut config)
+            .unwrap();
+        assert_eq!(writer, "13".as_bytes());
+        assert_eq!(
+            stats.to_csv(),
+            "function,calls,tokens,peak_stack\nmain,1,6,2\nsquare,2,4,3\n"
+        );
+    }
+
+    #[test]
+    fn test_recursion_limit() {
+        test_program_output!(
+            r#"
+        fn countdown (n) { n putu n if { n 1 - countdown } }
+        fn main { 3 countdown }
+        "#,
+            "3210".as_bytes()
+        );
+        let program = Program::parse("fn forever { forever } fn main { forever }").unwrap();
+        assert_eq!(
+            program.interpret(&mut Io::new(vec![])),
+            Err(RuntimeError::RecursionLimit(RECURSION_LIMIT))
+        );
+        let mut config = Config {
+            recursion_limit: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(
+            program.interpret_with_config(&mut Io::new(vec![]), &mut config),
+            Err(RuntimeError::RecursionLimit(3))
+        );
+    }
+
+    #[test]
+    fn test_compile() {
+        // runs the code both ways, checking that the output and the values left on the stack match
+        let run = |code: &str| {
+            let program = Program::parse(code).unwrap();
+            let mut walked = vec![];
+            let walked_stack = program
+                .run(&mut Io::new(&mut walked))
+                .map(|machine| machine.stack().to_vec());
+            let mut compiled = vec![];
+            let compiled_stack = program
+                .compile()
+                .run_with_config(&mut Io::new(&mut compiled), &mut Config::default())
+                .map(|machine| machine.stack().to_vec());
+            assert_eq!(compiled_stack, walked_stack);
+            assert_eq!(compiled, walked);
+            (String::from_utf8(walked).unwrap(), walked_stack)
+        };
+        let (output, stack) = run(r#"
+        fn fib (n) -> (f) { n 2 < if { n } else { n 1 - fib n 2 - fib + } }
+        fn first -> (n) { 0 loop { 1 + dup 3 = if { return } } }
+        fn stop { break }
+        fn main {
+            15 fib putu ' ' putc
+            0 loop outer {
+                1 + dup putu
+                loop {
+                    dup 3 = if { break outer }
+                    dup 1 = if { continue outer }
+                    ',' putc break
+                }
+            }
+            putu ' ' putc
+            0 for i 0 10 { i 2 % ? { i } : { 0 } + i 7 = if { 100 => i i putu } } putu ' ' putc
+            3 for { 2 for { 7 putu } } ' ' putc
+            1 2 let a b { a putu b putu a b + => a a putu } ' ' putc
+            0 while { dup 6 < } {
+                1 + dup match { case 2 { continue } case 4 { 'f' putc } else { dup putu } }
+            } drop ' ' putc
+            0 loop { 1 + dup 3 = if { stop } } putu ' ' putc
+            first putu " done" puts
+            1 2
+        }
+        "#);
+        assert_eq!(output, "610 12,33 10025 777777 213 13f56 3 3 done");
+        assert_eq!(stack, Ok(vec![1, 2]));
+
+        assert_eq!(run("fn main { 1 0 / }").1, Err(RuntimeError::DivByZero));
+        assert_eq!(
+            run("fn pair (a b) { } fn main { 1 pair }").1,
+            Err(RuntimeError::StackUnderflow(String::from("pair")))
+        );
+        assert_eq!(
+            run("fn main { 0 for i 1 3 { i 4 + } }").1,
+            Ok(vec![0, 5, 6])
+        );
+        // returning from inside a `for` inside a `match`
+        let (output, stack) = run(r#"
+        fn classify (n) -> (c) {
+            n match {
+                case 0 { 'z' return }
+                case 1 { 'o' }
+                else { for i 0 n { i 3 = if { 'x' return } } 'm' }
+            }
+        }
+        fn main { for i 0 6 { i classify putc } 7 }
+        "#);
+        assert_eq!(output, "zommxx");
+        assert_eq!(stack, Ok(vec![7]));
+
+        let program = Program::parse("fn main { 1 yield 2 }").unwrap();
+        let mut io = Io::new(std::io::sink());
+        assert_eq!(
+            program.compile().interpret(&mut io),
+            Err(RuntimeError::NotCompilable("yield"))
+        );
+        let mut stats = RunStats::default();
+        let mut config = Config {
+            stats: Some(&mut stats),
+            ..Config::default()
+        };
+        assert_eq!(
+            program
+                .compile()
+                .run_with_config(&mut io, &mut config)
+                .map(|_| ()),
+            Err(RuntimeError::NotCompilable("stats"))
+        );
+    }
+
+    #[test]
+    fn test_fibonacci() {
+        // exponentially many calls, so the time it takes shows the cost of a call
+        let program = Program::parse(
+            r#"
+        fn fib (n) -> (f) {
+            n 2 < if { n } else { n 1 - fib n 2 - fib + }
+        }
+        fn main { 25 fib putu }
+        "#,
+        )
+        .unwrap();
+        let mut output = vec![];
+        let mut io = Io::new(&mut output);
+        assert_eq!(program.call("fib", vec![20], &mut io), Ok(vec![6765]));
+        program.interpret(&mut io).unwrap();
+        drop(io);
+        assert_eq!(String::from_utf8(output).unwrap(), "75025");
+    }
+
+    #[test]
+    fn test_match() {
+        let source = r#"
+        fn name {
+            match {
+                case 0 { 'z' putc }
+                case 1 { 'o' putc }
+                case 2 { 't' putc }
+                case 4 { 'f' putc }
+                case 'a' { 'a' putc }
+                else { '?' putc }
+            }
+        }
+        fn dense {
+            match {
+                case 10 { 'a' putc }
+                case 11 { 'b' putc }
+                case 13 { 'd' putc }
+                else { '?' putc }
+            }
+        }
+        fn main {
+            0 name 1 name 2 name 3 name 4 name 97 name 98 name
+            9 dense 10 dense 11 dense 12 dense 13 dense 14 dense 0 1 - dense
+            0 while { dup 5 < } { 1 + dup match { case 2 { continue } case 4 { break } } dup putu }
+        }
+        "#;
+        test_program_output!(source, "zot?fa??ab?d??13".as_bytes());
+        let program = Program::parse(source).unwrap();
+        let table = |function: &str| match &program.functions[function].body[0] {
+            Token::MatchBlock(_, _, table) => table.as_ref().map(|table| table.start),
+            _ => unreachable!(),
+        };
+        assert_eq!(table("dense"), Some(10));
+        assert_eq!(table("name"), None);
+        assert_eq!(
+            Program::parse("fn main { match { case 1 { } case 0x1 { } } }")
+                .unwrap_err()
+                .kind,
+            ParseErrorKind::DuplicateCase(String::from("0x1"))
+        );
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        test_program_output!("fn main { 17 5 / putu 17 5 % putu }", "32".as_bytes());
+        for (code, error) in [
+            ("fn main { 1 0 / }", RuntimeError::DivByZero),
+            ("fn main { 1 0 % }", RuntimeError::DivByZero),
+            (
+                "fn main { 0x110000 putc }",
+                RuntimeError::InvalidChar(0x110000),
+            ),
+            (
+                "fn main { drop }",
+                RuntimeError::StackUnderflow(String::from("drop")),
+            ),
+        ] {
+            let program = Program::parse(code).unwrap();
+            assert_eq!(program.interpret(&mut Io::new(vec![])), Err(error));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "error: stack underflow in `drop`")]
+    fn test_interpret_or_panic() {
+        let program = Program::parse("fn main { drop }").unwrap();
+        program.interpret_or_panic(&mut Io::new(vec![]));
+    }
+
+    #[test]
+    fn test_byte_bounds() {
+        for (code, address) in [
+            ("fn main { 100 7 -> }", 100),
+            ("fn main { 4 alloc 4 + 1 -> }", 4),
+            ("fn main { 4 alloc drop 0 1 - <- }", usize::MAX),
+        ] {
+            let program = Program::parse(code).unwrap();
+            assert_eq!(
+                program.interpret(&mut Io::new(vec![])),
+                Err(RuntimeError::OutOfBounds(address))
+            );
+        }
+    }
+
+    #[test]
+    fn test_map() {
+        let code = r#"
+            fn main {
+                map_new let table {
+                    for i 0 20 { table i 100 * i map_set }
+                    table 7 'k' map_set
+                    table 8 'k' map_set
+                    table 3 map_get putu putu
+                    table 19 map_get putu putu
+                    table 'k' map_get putu putu
+                    table 20 map_get putu putu
+                }
+            }
+        "#;
+        let mut output = vec![];
+        Program::parse(code)
+            .unwrap()
+            .interpret(&mut Io::new(&mut output))
+            .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "1300119001800");
+
+        let error = |code| {
+            Program::parse(code)
+                .unwrap()
+                .interpret(&mut Io::new(vec![]))
+        };
+        assert_eq!(
+            error("fn main { 8 alloc 1 2 map_set }"),
+            Err(RuntimeError::InvalidMap(0))
+        );
+        assert_eq!(
+            error("fn main { 100 1 map_get }"),
+            Err(RuntimeError::InvalidMap(100))
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut program = Program::parse("fn main { 3 putu }").unwrap();
+        let library = Program::parse(
+            "global counter 8 fn square (a) { a a * } fn bump { counter incr putu 7 square putu }",
+        )
+        .unwrap();
+        program.merge(library).unwrap();
+        let mut output = vec![];
+        program
+            .interpret_with_config(
+                &mut Io::new(&mut output),
+                &mut Config {
+                    entry_point: Some("bump"),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "149");
+
+        let parse = |code| Program::parse(code).unwrap();
+        assert_eq!(
+            parse("fn main { } fn a { }").merge(parse("fn b { } fn main { 1 }")),
+            Err(MergeError::DuplicateFunction(String::from("main")))
+        );
+        assert_eq!(
+            parse("global a 8").merge(parse("global b 8")),
+            Err(MergeError::OverlappingGlobals)
+        );
+        let mut program = parse("fn main { }");
+        assert_eq!(program.merge(parse("global b 8 fn b { b }")), Ok(()));
+        assert_eq!(program.globals, vec![8]);
+    }
+
+    #[test]
+    fn test_read_only_literals() {
+        let interpret = |code, read_only_literals| {
+            let mut output = vec![];
+            Program::parse(code)
+                .unwrap()
+                .interpret_with_config(
+                    &mut Io::new(&mut output),
+                    &mut Config {
+                        read_only_literals,
+                        ..Default::default()
+                    },
+                )
+                .map(|_| String::from_utf8(output).unwrap())
+        };
+        let store = r#"fn main { "abc" dup 1 + 'x' -> puts }"#;
+        assert_eq!(interpret(store, false), Ok(String::from("axc")));
+        assert_eq!(
+            interpret(store, true),
+            Err(RuntimeError::WriteToReadOnly(1))
+        );
+        for code in [
+            r#"fn main { "abcdefgh" 0 w-> }"#,
+            r#"fn main { "ab" dup 2 memcpy }"#,
+            r#"fn main { "ab" 'c' 2 memset }"#,
+            r#"fn main { "abcdefgh" incr }"#,
+            r#"fn main { "a" 1 sinit 'b' spush }"#,
+        ] {
+            assert_eq!(interpret(code, true), Err(RuntimeError::WriteToReadOnly(0)));
+        }
+        // copying out of a literal is fine, and freed literal memory can be reused for anything
+        assert_eq!(
+            interpret(
+                r#"fn main { 4 alloc "ab" 3 memcpy "cd" 3 free 3 alloc 'e' -> 0 puts }"#,
+                true
+            ),
+            Ok(String::from("ab"))
+        );
+    }
+
+    #[test]
+    fn test_interpret_into() {
+        let interpret = |code| {
+            Program::parse(code)
+                .unwrap()
+                .interpret_into(&mut Io::new(vec![]))
+        };
+        assert_eq!(interpret("fn main { 2 3 + }"), Ok(vec![5]));
+        assert_eq!(interpret("fn main { }"), Ok(vec![]));
+        assert_eq!(
+            interpret("fn pair -> (a b) { 1 2 } fn main { pair 7 swap }"),
+            Ok(vec![1, 7, 2])
+        );
+        assert_eq!(interpret("fn main { 1 0 / }"), Err(RuntimeError::DivByZero));
+    }
+
+    #[test]
+    fn test_interpret_with_stack() {
+        let interpret = |code, initial| {
+            Program::parse(code)
+                .unwrap()
+                .interpret_with_stack(initial, &mut Io::new(vec![]))
+        };
+        assert_eq!(interpret("fn main { * }", vec![4, 5]), Ok(vec![20]));
+        assert_eq!(
+            interpret("fn main { - }", vec![9, 2, 5]),
+            Ok(vec![9, 2_usize.wrapping_sub(5)])
+        );
+        assert_eq!(
+            interpret("fn main (a b) { a b - }", vec![9, 2]),
+            Ok(vec![7])
+        );
+        assert_eq!(interpret("fn main { 1 }", vec![]), Ok(vec![1]));
+        assert_eq!(
+            interpret("fn main { + }", vec![1]),
+            Err(RuntimeError::StackUnderflow(String::from("+")))
+        );
+    }
+
+    #[test]
+    fn test_call() {
+        let program = Program::parse(
+            "fn square { dup * } fn divmod (a b) -> (q r) { a b / a b % } fn main { 1 putu }",
+        )
+        .unwrap();
+        let mut output = vec![];
+        let mut io = Io::new(&mut output);
+        assert_eq!(program.call("square", vec![7], &mut io), Ok(vec![49]));
+        assert_eq!(
+            program.call("divmod", vec![1, 17, 5], &mut io),
+            Ok(vec![1, 3, 2])
+        );
+        assert_eq!(
+            program.call("cube", vec![2], &mut io),
+            Err(RuntimeError::UnknownFunction(String::from("cube")))
+        );
+        assert_eq!(
+            program.call("square", vec![], &mut io),
+            Err(RuntimeError::StackUnderflow(String::from("dup")))
+        );
+        // main is never run by calls to other functions
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_yield() {
+        let first = Program::parse("fn main { for i 0 3 { 'a' i + putc yield } }").unwrap();
+        let second = Program::parse(
+            "fn count (n) { for i 0 n { i putu yield } } fn main { 2 count 9 putu }",
+        )
+        .unwrap();
+        let mut config = Config::default();
+        let mut writer = vec![];
+        let mut io = Io::new(&mut writer);
+        let mut machines = [
+            Machine::new(&first, &mut config).unwrap(),
+            Machine::new(&second, &mut config).unwrap(),
+        ];
+        let mut results = vec![];
+        for _ in 0..4 {
+            for machine in &mut machines {
+                results.push(machine.resume(&mut io, &mut config).unwrap());
+            }
+        }
+        assert_eq!(writer, "a0b1c9".as_bytes());
+        // a yield ending the run finishes it
+        assert_eq!(
+            results,
+            [[StepResult::Yielded; 4], [StepResult::Finished; 4]].concat()
+        );
+        test_program_output!("fn main { 1 putu yield 2 putu }", "12".as_bytes());
+    }
+
+    #[test]
+    fn test_run() {
+        let program = Program::parse(
+            r#"
+        global buffer 3
+        fn main { 1 2 3 + buffer "abc" 3 memcpy buffer 1 + 'z' -> }
+        "#,
+        )
+        .unwrap();
+        let machine = program.run(&mut Io::new(vec![])).unwrap();
+        assert_eq!(machine.stack(), vec![1, 5]);
+        assert_eq!(machine.memory().get_range(0, 3), Some("azc".as_bytes()));
+        assert_eq!(machine.call_depth(), 0);
+    }
+
+    #[test]
+    fn test_conditional() {
+        test_program_output!(
+            r#"
+        fn max (a b) { a b > ? { a } : { b } }
+        fn main { 3 7 max putu 9 2 max putu 0 ? { 10 2 * } : { 4 5 + } putu }
+        "#,
+            "799".as_bytes()
+        );
+        assert_eq!(
+            Program::parse("fn main { 1 ? { 1 2 } : { 3 } }")
+                .unwrap_err()
+                .kind,
+            ParseErrorKind::ConditionalValue
+        );
+        assert_eq!(
+            Program::parse("fn main { 1 ? { 1 } else { 3 } }")
+                .unwrap_err()
+                .kind,
+            ParseErrorKind::UnexpectedToken {
+                found: String::from("else"),
+                expected: ":"
+            }
+        );
+    }
+
+    #[test]
+    fn test_results() {
+        test_program_output!(
+            r#"
+        fn divmod (a b) -> (q r) { a b / a b % }
+        fn digits (n) -> (count) {
+            n 10 < if { 1 } else { n 10 / digits 1 + }
+        }
+        fn sum (n) -> (total) {
+            0 for i 0 n { i + }
+        }
+        fn main { 17 5 divmod putu putu 12345 digits putu 4 sum putu }
+        "#,
+            "2356".as_bytes()
+        );
+        assert_eq!(
+            Program::parse("fn divmod (a b) -> (q r) { a b / }")
+                .unwrap_err()
+                .kind,
+            ParseErrorKind::ResultCount {
+                function: String::from("divmod"),
+                expected: 2,
+                found: 1
+            }
+        );
+        assert_eq!(
+            Program::parse("fn pair -> (a b) { 1 if { 1 2 } else { 3 } }")
+                .unwrap_err()
+                .kind,
+            ParseErrorKind::UnknownStackEffect(String::from("pair"))
+        );
+        let program = Program::parse("fn none -> () { loop { 1 if { break } } }").unwrap();
+        assert!(program.disassemble().starts_with("fn none -> () {"));
+    }
+
+    #[test]
+    fn test_pure_functions() {
+        let source = r#"
+        fn square pure { dup * }
+        fn clamp (value) pure { value 10 > if { 10 } else { value } }
+        fn countdown (n) pure { n if { n 1 - countdown } }
+        fn main {
+            3 square putu
+            1 2 + 4 clamp 20 clamp + + putu
+            getu square putu
+            5 countdown
+        }
+        "#;
+        let program = Program::parse(source).unwrap();
+        let main = &program.functions["main"].body;
+        let main: Vec<String> = main
+            .iter()
+            .map(|token| program.functions.display(token))
+            .collect();
+        assert_eq!(
+            main,
+            [
+                "9",
+                "putu",
+                "1",
+                "2",
+                "+",
+                "4",
+                "10",
+                "+",
+                "+",
+                "putu",
+                "getu",
+                "square",
+                "putu",
+                "5",
+                "countdown"
+            ]
+        );
+        let mut writer = vec![];
+        let mut io = Io::with_reader(&mut writer, "7".as_bytes());
+        program.interpret(&mut io).unwrap();
+        assert_eq!(writer, "91749".as_bytes());
+
+        assert_eq!(
+            Program::parse("fn shout pure { 'a' putc }")
+                .unwrap_err()
+                .kind,
+            ParseErrorKind::ImpureFunction {
+                function: String::from("shout"),
+                token: String::from("putc")
+            }
+        );
+        assert_eq!(
+            Program::parse("fn shout { } fn quiet pure { 1 if { shout } }")
+                .unwrap_err()
+                .kind,
+            ParseErrorKind::ImpureFunction {
+                function: String::from("quiet"),
+                token: String::from("shout")
+            }
+        );
+
+        // counted loops are not folded, their counts can be large enough to hang the parser
+        let program = Program::parse(
+            "fn spin pure { for { } 0 } fn main { 0 if { 1000000000000 spin putu } 7 putu }",
+        )
+        .unwrap();
+        assert!(program
+            .disassemble()
+            .contains("1000000000000\n        spin\n"));
+        test_program_output!(
+            "fn twice (n) pure { 0 2 for { n + } } fn main { 3 twice putu }",
+            "6".as_bytes()
+        );
+        // calls taking more steps than the folding allows are left to run
+        let source = format!(
+            "fn long pure {{ 0 {} }} fn main {{ long putu }}",
+            "1 + ".repeat(FOLD_LIMIT)
+        );
+        let program = Program::parse(&source).unwrap();
+        assert!(program.disassemble().contains("    long\n"));
+        test_program_output!(&source, FOLD_LIMIT.to_string().as_bytes());
+        let program = Program::parse("fn short pure { 0 1 + } fn main { short putu }").unwrap();
+        assert!(!program.disassemble().contains("    short\n"));
+    }
+
+    #[test]
+    fn test_elif() {
+        test_program_output!(
+            r#"
+        fn classify (n) {
+            n 10 < n 0 = if { 'z' } elif { 's' } else { 'l' } putc
+        }
+        fn name (n) {
+            n 2 = n 1 = n 0 = if { 'a' } elif { 'b' } elif { 'c' } else { '?' } putc
+        }
+        fn main {
+            7 0 classify 5 classify 50 classify
+            0 name 2 name 9 name
+            1 if { 'y' putc } else { 'n' putc }
+            putu
+        }
+        "#,
+            "zslac?y7".as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_for() {
+        test_program_output!("fn main { for i 0 5 { i putu } }", "01234".as_bytes());
+        test_program_output!(
+            r#"
+        fn three { 3 }
+        fn main {
+            10 let end {
+                for i three end {
+                    i 5 = if { continue }
+                    i 8 = if { break }
+                    for j 0 i { j drop }
+                    i putu
+                }
+            }
+            for i 5 5 { i putu }
+        }
+        "#,
+            "3467".as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_repeat() {
+        test_program_output!("fn main { 1 2 3 2 for { + } putu }", "6".as_bytes());
+        test_program_output!(
+            r#"
+        fn main {
+            0 for { 1 putu }
+            0 5 for { 1 + dup 2 = if { continue } dup 4 = if { break } dup putu }
+            putu
+            3 for { 2 for { 7 putu } }
+        }
+        "#,
+            "134777777".as_bytes()
+        );
+        assert_eq!(
+            Program::parse("fn main { for { 1 } }")
+                .unwrap()
+                .interpret(&mut Io::new(vec![])),
+            Err(RuntimeError::StackUnderflow(String::from("for")))
+        );
+    }
+
+    #[test]
+    fn test_labeled_loops() {
+        test_program_output!(
+            r#"
+        fn main {
+            0 loop outer {
+                1 + dup putu
+                loop {
+                    dup 3 = if { break outer }
+                    dup 1 = if { continue outer }
+                    ',' putc break
+                }
+            }
+            putu
+            0 while counting { dup 5 < } {
+                1 + 1 for { dup 2 % if { continue counting } break counting }
+            }
+            putu
+        }
+        "#,
+            "12,332".as_bytes()
+        );
+        let program = Program::parse(
+            r#"
+        fn count -> (n) { 0 loop a { loop { 1 + dup 5 = if { break a } } } }
+        fn main { count putu }
+        "#,
+        )
+        .unwrap();
+        assert!(program.disassemble().contains("    loop a {\n"));
+        assert!(program.disassemble().contains("break a\n"));
+        for code in [
+            "fn main { loop { break outer } }",
+            "fn inner { break outer } fn main { loop outer { inner } }",
+        ] {
+            assert_eq!(
+                Program::parse(code).unwrap_err().kind,
+                ParseErrorKind::UndefinedFunction(String::from("outer"))
+            );
+        }
+    }
+
+    #[test]
+    fn test_record_input() {
+        let program = Program::parse(
+            r#"
+        fn main {
+            getc putc getu 2 * putu
+            readline putu drop
+            getc puti
+        }
+        "#,
+        )
+        .unwrap();
+        let mut recorded = vec![];
+        let mut io = Io::with_reader(&mut recorded, "a21\nline\n".as_bytes()).recording(vec![]);
+        program.interpret(&mut io).unwrap();
+        let log = io.reader.into_log();
+        assert_eq!(log, "a21\nline\n".as_bytes());
+
+        let mut replayed = vec![];
+        program
+            .interpret(&mut Io::with_reader(&mut replayed, log.as_slice()))
+            .unwrap();
+        assert_eq!(replayed, recorded);
+        assert_eq!(replayed, "a425-1".as_bytes());
+    }
+
+    #[test]
+    fn test_hook() {
+        let program = Program::parse("fn main { 1 2 + putu 7 5 40 + putu }").unwrap();
+        let mut seen = Vec::new();
+        let mut hook = |token: &Token, stack: &[usize]| {
+            seen.push((token.to_string(), stack.to_vec()));
+            !matches!(token, Token::Push(40))
+        };
+        let mut config = Config {
+            hook: Some(&mut hook),
+            ..Default::default()
+        };
+        let mut writer = vec![];
+        program
+            .interpret_with_config(&mut Io::new(&mut writer), &mut config)
+            .unwrap();
+        assert_eq!(writer, "312".as_bytes());
+        assert_eq!(seen[0], (String::from("1"), vec![]));
+        assert_eq!(seen[2], (String::from("+"), vec![1, 2]));
+        assert_eq!(seen.len(), 9);
+    }
+
+    #[test]
+    fn test_step() {
+        let program = Program::parse("fn main { 69 putu }").unwrap();
+        let mut config = Config::default();
+        let mut writer = vec![];
+        let mut io = Io::new(&mut writer);
+        let mut machine = Machine::new(&program, &mut config).unwrap();
+        assert_eq!(machine.stack(), []);
+        assert_eq!(machine.call_depth(), 1);
+        assert_eq!(machine.next_token().unwrap().to_string(), "69");
+        assert_eq!(machine.step(&mut io, &mut config), Ok(StepResult::Running));
+        assert_eq!(machine.stack(), [69]);
+        assert_eq!(machine.instruction_pointer(), Some(1));
+        assert_eq!(machine.step(&mut io, &mut config), Ok(StepResult::Finished));
+        assert_eq!(machine.stack(), []);
+        assert!(machine.next_token().is_none());
+        assert_eq!(machine.step(&mut io, &mut config), Ok(StepResult::Finished));
+        assert_eq!(writer, "69".as_bytes());
+    }
+
+    #[test]
+    fn test_return() {
+        test_program_output!(
+            r#"
+        fn check (n) { n 3 > if { 1 putu return } n putu }
+        fn find (n) -> (index) {
+            for i 0 10 { i i * n = if { i return } }
+            0
+        }
+        fn main { 5 check 2 check 49 find putu 50 find putu 'x' putc }
+        "#,
+            "1270x".as_bytes()
+        );
+        assert_eq!(
+            Program::parse("fn pair -> (a b) { 1 if { 1 return } 1 2 }")
+                .unwrap_err()
+                .kind,
+            ParseErrorKind::UnknownStackEffect(String::from("pair"))
+        );
+    }
+
+    #[test]
+    fn test_set_let() {
+        test_program_output!(
+            r#"
+        fn double (a) { a 2 * => a a putu }
+        fn main {
+            0 let count {
+                while { count 5 < } { count 1 + => count }
+                count putu
+            }
+            1 let x { 2 let x { 3 => x x putu } x putu }
+            for i 0 3 { i 10 + => i i putu }
+            4 double
+        }
+        "#,
+            "5311011128".as_bytes()
+        );
+        assert_eq!(
+            Program::parse("fn main { 1 let x { 2 => y } }")
+                .unwrap_err()
+                .kind,
+            ParseErrorKind::UnexpectedToken {
+                found: String::from("y"),
+                expected: "let binding"
+            }
+        );
+    }
+
+    #[test]
+    fn test_word_memory() {
+        test_program_output!(
+            r#"
+        fn main {
+            16 alloc
+            let address {
+                address 305419896 w->
+                address 8 + -1 w->
+                address w<- putu
+                address 8 + w<- puti
+                address <- putu
+            }
+        }
+        "#,
+            "305419896-1120".as_bytes()
+        );
+        // every byte of the word is stored, lowest first
+        test_program_output!(
+            r#"
+        fn main {
+            8 alloc
+            let address {
+                address 0x0102030405060708 w->
+                address w<- putu
+                address <- putu address 7 + <- putu
+            }
+        }
+        "#,
+            "7262385979038285681".as_bytes()
+        );
+        for code in ["fn main { 4 alloc w<- }", "fn main { 12 alloc 8 + 1 w-> }"] {
+            let program = Program::parse(code).unwrap();
+            assert!(matches!(
+                program.interpret(&mut Io::new(vec![])),
+                Err(RuntimeError::OutOfBounds(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let program = Program::parse(
+            r#"
+        fn square { dup * }
+        fn main {
+            "hi" 3 free
+            0 while { dup 3 < } {
+                dup square putu
+                1 +
+            }
+            let n {
+                n 1 = if { loop { break } } else { n putu }
+            }
+        }
+        "#,
+        )
+        .unwrap();
+        let expected = r#"fn main {
+    "hi"
+    3
+    free
+    0
+    while {
+        dup
+        3
+        <
+    } {
+        dup
+        square
+        putu
+        1
+        +
+    }
+    let n {
+        n
+        1
+        =
+        if {
+            loop {
+                break
+            }
+        } else {
+            n
+            putu
+        }
+    }
+}
+fn square {
+    dup
+    *
+}
+"#;
+        assert_eq!(program.disassemble(), expected);
+    }
+
+    #[test]
+    fn test_memcpy() {
+        test_program_output!(
+            r#"
+        fn main {
+            "abc" 4 alloc
+            let destination source {
+                destination source 4 memcpy
+                destination <- putc destination 2 + <- putc
+            }
+        }
+        "#,
+            "ac".as_bytes()
+        );
+        // overlapping regions behave as if copied through a temporary buffer
+        test_program_output!(
+            r#"
+        fn main {
+            "abcde"
+            let text {
+                text 1 + text 4 memcpy
+                text <- putc text 1 + <- putc text 4 + <- putc
+                text text 2 + 3 memcpy
+                text <- putc text 1 + <- putc text 2 + <- putc
+            }
+        }
+        "#,
+            "aadbcd".as_bytes()
+        );
+        let program = Program::parse(r#"fn main { 8 alloc "abc" 10 memcpy }"#).unwrap();
+        assert_eq!(
+            program.interpret(&mut Io::new(vec![])),
+            Err(RuntimeError::OutOfBounds(8))
+        );
+    }
+
+    #[test]
+    fn test_memset() {
+        test_program_output!(
+            r#"
+        fn main {
+            5 alloc
+            let buffer {
+                buffer 0xAB 5 memset
+                0 while { dup 5 < } { dup buffer + <- putu 32 putc 1 + } drop
+            }
+        }
+        "#,
+            "171 171 171 171 171 ".as_bytes()
+        );
+        let program = Program::parse("fn main { 5 alloc 0 6 memset }").unwrap();
+        assert_eq!(
+            program.interpret(&mut Io::new(vec![])),
+            Err(RuntimeError::OutOfBounds(0))
+        );
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        test_program_output!(
+            r#"
+        fn main {
+            "kitten" 6 "sitting" 7 editdist putu
+            "flaw" 4 "lawn" 4 editdist putu
+            "abc" 0 "abc" 3 editdist putu
+        }
+        "#,
+            "323".as_bytes()
+        );
+        let program = Program::parse(r#"fn main { "kitten" 6 "sitting" 7 editdist }"#).unwrap();
+        let mut config = Config {
+            edit_distance_limit: Some(41),
+            ..Default::default()
+        };
+        assert_eq!(
+            program.interpret_with_config(&mut Io::new(vec![]), &mut config),
+            Err(RuntimeError::EditDistanceLimit {
+                cells: 42,
+                limit: 41
+            })
+        );
+    }
+
+    #[test]
+    fn test_crc32() {
+        test_program_output!(
+            r#"
+        fn main {
+            "123456789" 9 crc32 putu 32 putc
+            "" 0 crc32 putu 32 putc
+            "The quick brown fox jumps over the lazy dog" 43 crc32 putu
+        }
+        "#,
+            format!("{} 0 {}", 0xCBF43926u32, 0x414FA339u32).as_bytes()
+        );
+        assert_eq!(
+            Program::parse("fn main { 8 alloc 9 crc32 }")
+                .unwrap()
+                .interpret(&mut Io::new(vec![])),
+            Err(RuntimeError::OutOfBounds(0))
+        );
+    }
+
+    #[test]
+    fn test_format_base() {
+        test_program_output!(
+            r#"
+        fn print (address len) { for i 0 len { address i + <- putc } }
+        fn main {
+            255 16 itoa_base print 32 putc
+            5 2 itoa_base print 32 putc
+            0 36 itoa_base print 32 putc
+            35 36 itoa_base print
+        }
+        "#,
+            "ff 101 0 z".as_bytes()
+        );
+        for base in [0, 1, 37] {
+            let program = Program::parse(&format!("fn main {{ 10 {base} itoa_base }}")).unwrap();
+            assert_eq!(
+                program.interpret(&mut Io::new(vec![])),
+                Err(RuntimeError::InvalidBase(base))
+            );
+        }
+    }
+
+    #[test]
+    fn test_redirect() {
+        test_program_output!(
+            r#"
+        fn main {
+            'a' putc
+            redirect
+                12 putu
+                redirect 'x' putc endredirect
+                let len address { len putu address <- putc }
+            endredirect
+            'b' putc
+            let len address {
+                len putu
+                address <- putc address 1 + <- putc address 2 + <- putc address 3 + <- putc
+            }
+        }
+        "#,
+            "ab4121x".as_bytes()
+        );
+        let program = Program::parse("fn main { endredirect }").unwrap();
+        assert_eq!(
+            program.interpret(&mut Io::new(vec![])),
+            Err(RuntimeError::UnbalancedRedirect)
+        );
+    }
+
+    #[test]
+    fn test_compile_to_rust() {
+        let source = std::fs::read_to_string("examples/hello_world.rsl").unwrap();
+        let program = Program::parse(&source).unwrap();
+        let generated = program.compile_to_rust();
+        assert!(generated.contains("fn f_main(m: &mut Machine) {"));
+        assert!(generated.contains("fn f_print(m: &mut Machine) {"));
+        assert!(generated.contains("let mut v_address = m.pop();"));
+        assert!(generated.contains("f_print(m);"));
+
+        let directory = std::env::temp_dir().join(format!("stack_lang_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let mut expected = vec![];
+        for (name, source) in [
+            ("hello_world", source.clone()),
+            (
+                "fibonacci",
+                std::fs::read_to_string("examples/fibonacci.rsl").unwrap(),
+            ),
+            (
+                "operations",
+                String::from(
+                    r#"
+            global counter 8
+            fn sub (a b) { a b - }
+            fn main {
+                7 2 sub putu
+                3 match { case 3 { 'm' putc } case 4 { } else { 'e' putc } }
+                for i 0 3 { for j i 3 { j putu } }
+                0 loop {
+                    1 + dup 3 = if { continue }
+                    dup 6 > if { break }
+                    dup putu
+                }
+                -3 puti 12 10 ^ 2 << 1 >> putu 'a' toupper putc
+                5 0 and 3 not or putu
+                counter incr counter incr putu drop
+                redirect 1 2 3 rot over swap drop putu putu endredirect
+                let len address { address <- putc len putu }
+                "ab" 8 alloc let destination source {
+                    destination source 3 memcpy
+                    destination 1 + <- putc
+                    destination 305419896 w-> destination w<- putu
+                }
+                "crc" 3 crc32 putu
+                depth putu 1 2 3 nip tuck 2dup + + + + putu 4 5 2drop 6 1 pick putu
+                0 ? { 2 } : { 3 } putu
+                0 let n { for i 0 3 { n i + => n 7 => i } n putu }
+                2 for { 'r' putc }
+                3 alloc 3 sinit 'p' spush 'q' spush spop putc spop putc
+                0 loop outer { 1 + while inner { 1 } { dup 2 < if { continue outer } break outer } } putu
+                loop { 1 putu yield return } "hi" puts
+                4 ralloc dup incref dup decref dup raddr 'x' -> dup raddr puts decref
+                map_new dup 5 'v' map_set dup 'v' map_get putu putu 'w' map_get putu putu
+            }
+            "#,
+                ),
+            ),
+            (
+                "leave",
+                String::from(
+                    r#"
+            fn stop { break }
+            fn relay { stop }
+            fn skip (n) { n 2 = if { continue } }
+            fn main {
+                0 loop { 1 + dup 3 > if { stop } } putu
+                for i 0 5 { i skip i putu }
+                loop outer { 'a' putc loop { relay } 'b' putc break outer } 'c' putc
+                0 while { dup 9 < } { 1 + dup 4 = if { relay } } putu
+            }
+            "#,
+                ),
+            ),
+        ] {
+            let program = Program::parse(&source).unwrap();
+            let rust_source = directory.join(format!("{name}.rs"));
+            let binary = directory.join(name);
+            std::fs::write(&rust_source, program.compile_to_rust()).unwrap();
+            let status = std::process::Command::new(
+                std::env::var("RUSTC").unwrap_or_else(|_| String::from("rustc")),
+            )
+            .arg(&rust_source)
+            .args(["--edition", "2021", "-o"])
+            .arg(&binary)
+            .status()
+            .unwrap();
+            assert!(status.success());
+            let output = std::process::Command::new(&binary).output().unwrap();
+
+            expected.clear();
+            program.interpret(&mut Io::new(&mut expected)).unwrap();
+            expected.push(b'\n');
+            assert_eq!(output.stdout, expected);
+        }
+        std::fs::remove_dir_all(&directory).unwrap();
     }
 }
@@ -0,0 +1,252 @@
+use super::{
+    stack::Stack, Config, Definitions, Function, Machine, Memory, ParseError, ParseErrorKind,
+    Program, RuntimeError, StepResult, Tokens,
+};
+use crate::io::Io;
+use crate::token::Token;
+use derive_more::Display;
+use std::{
+    collections::HashSet,
+    io::{Read, Write},
+    path::Path,
+};
+
+// the function the code of a line is run as, no name in the source can refer to it
+const LINE: &str = "";
+
+#[derive(Debug, Display, PartialEq, Eq)]
+pub enum ReplError {
+    #[display(fmt = "error {}", _0)]
+    Parse(ParseError),
+    #[display(fmt = "error: {}", _0)]
+    Runtime(RuntimeError),
+    #[display(fmt = "error: globals can't be defined in the repl, allocate memory instead")]
+    Global,
+}
+
+// runs lines of code one at a time against the same stack and memory, keeping the definitions made on them
+pub struct Repl {
+    definitions: Definitions,
+    stack: Stack,
+    memory: Memory,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Self {
+            definitions: Definitions::default(),
+            stack: Stack::new(),
+            memory: Memory::new(),
+        }
+    }
+
+    pub fn stack(&self) -> &[usize] {
+        self.stack.values()
+    }
+
+    // reads lines until the end of input, writing the stack after every line that runs and the errors,
+    // a line ending inside of a block or a comment is continued by the next one
+    pub fn run<W: Write, R: Read>(&mut self, io: &mut Io<W, R>) -> std::io::Result<()> {
+        let mut pending = String::new();
+        loop {
+            let line = io.read_line()?;
+            if line.is_empty() {
+                return Ok(());
+            }
+            pending.push_str(&String::from_utf8_lossy(&line));
+            match self.eval(&pending, io) {
+                Err(ReplError::Parse(ParseError {
+                    kind: ParseErrorKind::UnexpectedEof | ParseErrorKind::UnterminatedComment,
+                    ..
+                })) => continue,
+                Err(error) => writeln!(io, "{error}")?,
+                Ok(()) => writeln!(io, "{:?}", self.stack)?,
+            }
+            io.flush()?;
+            pending.clear();
+        }
+    }
+
+    // a line starting with `fn`, `mod`, `const` or `include` adds definitions, any other one is run as code
+    pub fn eval<W: Write, R: Read>(
+        &mut self,
+        line: &str,
+        io: &mut Io<W, R>,
+    ) -> Result<(), ReplError> {
+        match Tokens::new(line.chars()).next() {
+            Some((token, _)) if token == "global" => Err(ReplError::Global),
+            Some((token, _)) if ["fn", "mod", "const", "include"].contains(&token.as_str()) => {
+                self.define(line)
+            }
+            _ => self.execute(line, io),
+        }
+    }
+
+    // nothing is defined unless the whole line parses
+    fn define(&mut self, line: &str) -> Result<(), ReplError> {
+        let functions = self.definitions.functions.len();
+        let constants: HashSet<String> = self.definitions.constants.keys().cloned().collect();
+        let globals = self.definitions.globals.len();
+        let result = Program::parse_definitions(
+            &mut Tokens::new(line.chars()),
+            &mut self.definitions,
+            Path::new(""),
+        );
+        let error = match result {
+            Err(error) => ReplError::Parse(error),
+            Ok(()) if self.definitions.globals.len() > globals => ReplError::Global,
+            Ok(()) => return Ok(()),
+        };
+        self.definitions.functions.truncate(functions);
+        self.definitions
+            .constants
+            .retain(|name, _| constants.contains(name));
+        self.definitions.globals.truncate(globals);
+        self.definitions.calls.clear();
+        Err(error)
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<Vec<Token>, ReplError> {
+        // the brace closes the line like the body of a function, so the blocks it opens are left unclosed
+        let mut code = Tokens::new(line.chars().chain(" }".chars()));
+        let mut calls = Vec::new();
+        let body = Program::parse_code_segment(
+            &mut code,
+            &mut self.definitions.functions,
+            &mut calls,
+            "",
+            &Vec::new(),
+            &[],
+            &self.definitions.constants,
+        )
+        .map_err(ReplError::Parse)?;
+        if let Some((token, span)) = code.next() {
+            return Err(ReplError::Parse(
+                ParseErrorKind::UnexpectedToken {
+                    found: token,
+                    expected: "end of line",
+                }
+                .at(span),
+            ));
+        }
+        if let Some(call) = calls.into_iter().next() {
+            let name = self.definitions.functions.name(call.index).to_string();
+            return Err(ReplError::Parse(
+                ParseErrorKind::UndefinedFunction(name).at(call.span),
+            ));
+        }
+        Ok(body)
+    }
+
+    fn execute<W: Write, R: Read>(
+        &mut self,
+        line: &str,
+        io: &mut Io<W, R>,
+    ) -> Result<(), ReplError> {
+        // names the line calls without them being defined are given indices, which are dropped with the line
+        let functions = self.definitions.functions.len();
+        let body = self
+            .parse_line(line)
+            .inspect_err(|_| self.definitions.functions.truncate(functions))?;
+
+        let mut program_functions = std::mem::take(&mut self.definitions.functions);
+        program_functions.insert(
+            LINE,
+            Function {
+                parameters: Vec::new(),
+                pure: false,
+                results: None,
+                body,
+            },
+        );
+        let program = Program {
+            functions: program_functions,
+            globals: Vec::new(),
+        };
+        let mut config = Config {
+            entry_point: Some(LINE),
+            ..Default::default()
+        };
+        let stack = std::mem::replace(&mut self.stack, Stack::new());
+        let memory = std::mem::take(&mut self.memory);
+        let result =
+            Machine::with_state(&program, stack, memory, &mut config).and_then(|mut machine| {
+                let result = loop {
+                    match machine.step(io, &mut config) {
+                        Ok(StepResult::Running | StepResult::Yielded) => {}
+                        Ok(StepResult::Finished) => break Ok(()),
+                        Err(error) => break Err(error),
+                    }
+                };
+                (self.stack, self.memory) = machine.into_state();
+                result
+            });
+        self.definitions.functions = program.functions;
+        self.definitions.functions.truncate(functions);
+        if result.is_err() {
+            // the frames pushed by a line that failed are not going to be popped
+            while self.stack.pop_frame().is_some() {}
+        }
+        result.map_err(ReplError::Runtime)
+    }
+}
+
+#[test]
+fn test_repl() {
+    let input = [
+        "1 2",
+        "+ dup",
+        "fn square { dup * }",
+        "square putu",
+        "const TEN 10",
+        "drop drop",
+        "TEN fn",
+        "unknown",
+        "fn countdown (n) {",
+        "    n while { dup } { dup putu 1 - }",
+        "}",
+        "3 countdown",
+        "1 if { 2 /* a",
+        " comment */ }",
+        "mod math { fn double (n) { n 2 * } }",
+        "mod math {",
+        "    fn triple (n) { n 3 * }",
+        "}",
+        "math::double math::triple",
+        "global table 8",
+        "1 }",
+    ]
+    .join("\n");
+    let mut output = vec![];
+    let mut repl = Repl::new();
+    repl.run(&mut Io::with_reader(&mut output, input.as_bytes()))
+        .unwrap();
+    let expected = [
+        "[1, 2]",
+        "[3, 3]",
+        "[3, 3]",
+        "9[3]",
+        "[3]",
+        "error: stack underflow in `drop`",
+        "error at line 1, col 5: unknown token `fn`",
+        "error at line 1, col 1: call to undefined function `unknown`",
+        "[]",
+        "321[0]",
+        "[0, 2]",
+        "[0, 2]",
+        "[0, 2]",
+        "[0, 12]",
+        "error: globals can't be defined in the repl, allocate memory instead",
+        "error at line 1, col 5: unexpected token `}`, expected `end of line`",
+        "",
+    ]
+    .join("\n");
+    assert_eq!(String::from_utf8(output).unwrap(), expected);
+    assert_eq!(repl.stack(), [0, 12]);
+}
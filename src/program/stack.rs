@@ -0,0 +1,105 @@
+use std::fmt::Debug;
+
+// the values below the base of the innermost frame can't be popped until the frame is removed
+pub struct Stack {
+    values: Vec<usize>,
+    frames: Vec<usize>, // bases of the frames, innermost last
+}
+
+impl Stack {
+    pub fn new() -> Self {
+        Self {
+            values: Vec::with_capacity(1000),
+            frames: Vec::new(),
+        }
+    }
+
+    fn base(&self) -> usize {
+        self.frames.last().copied().unwrap_or(0)
+    }
+
+    #[inline]
+    pub fn push(&mut self, value: usize) {
+        self.values.push(value);
+    }
+
+    pub fn extend(&mut self, values: impl IntoIterator<Item = usize>) {
+        self.values.extend(values);
+    }
+
+    #[inline]
+    pub fn pop(&mut self) -> Option<usize> {
+        if self.values.len() > self.base() {
+            self.values.pop()
+        } else {
+            None
+        }
+    }
+
+    // the value `index` positions below the top, None when it isn't visible in the innermost frame
+    pub fn peek(&self, index: usize) -> Option<usize> {
+        let position = self.values.len().checked_sub(index.checked_add(1)?)?;
+        (position >= self.base()).then(|| self.values[position])
+    }
+
+    // all of the values, including the ones hidden by frames
+    pub fn values(&self) -> &[usize] {
+        &self.values
+    }
+
+    // number of values visible in the innermost frame
+    pub fn len(&self) -> usize {
+        self.values.len() - self.base()
+    }
+
+    // number of values on the whole stack, including the ones hidden by frames
+    pub fn depth(&self) -> usize {
+        self.values.len()
+    }
+
+    // all of the values, None while a frame is still open
+    pub fn into_values(self) -> Option<Vec<usize>> {
+        self.frames.is_empty().then_some(self.values)
+    }
+
+    // restricts the stack to the top `len` values, fails if fewer are visible
+    pub fn push_frame(&mut self, len: usize) -> Option<()> {
+        if len > self.len() {
+            return None;
+        }
+        self.frames.push(self.values.len() - len);
+        Some(())
+    }
+
+    // the values left in the frame stay on the stack
+    pub fn pop_frame(&mut self) -> Option<()> {
+        self.frames.pop().map(|_| ())
+    }
+}
+
+// frames are not shown, so dumps look the same as for a plain vector
+impl Debug for Stack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.values.fmt(f)
+    }
+}
+
+#[test]
+fn test_stack() {
+    let mut stack = Stack::new();
+    stack.push(1);
+    stack.push(2);
+    stack.push(3);
+    assert_eq!(stack.push_frame(4), None);
+    assert_eq!(stack.push_frame(2), Some(()));
+    assert_eq!(stack.len(), 2);
+    assert_eq!(stack.peek(1), Some(2));
+    assert_eq!(stack.peek(2), None);
+    assert_eq!(stack.pop(), Some(3));
+    assert_eq!(stack.pop(), Some(2));
+    assert_eq!(stack.pop(), None);
+    stack.push(4);
+    assert_eq!(stack.pop_frame(), Some(()));
+    assert_eq!(format!("{stack:?}"), "[1, 4]");
+    assert_eq!(stack.pop_frame(), None);
+}
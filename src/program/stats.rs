@@ -0,0 +1,77 @@
+use std::collections::BTreeMap;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FunctionStats {
+    pub calls: usize,
+    pub tokens: usize, // tokens executed directly in the function, excluding the functions it calls
+    pub peak_stack: usize, // deepest the whole stack got while running the function's own tokens
+}
+
+// per function counters collected while interpreting with `Config::stats` set
+#[derive(Debug, Default)]
+pub struct RunStats {
+    pub functions: BTreeMap<String, FunctionStats>,
+    calls: Vec<String>, // the functions currently running, innermost last
+}
+
+impl RunStats {
+    pub(super) fn enter(&mut self, function: &str, depth: usize) {
+        let stats = self.functions.entry(function.to_string()).or_default();
+        stats.calls += 1;
+        stats.peak_stack = stats.peak_stack.max(depth);
+        self.calls.push(function.to_string());
+    }
+
+    pub(super) fn exit(&mut self, depth: usize) {
+        self.update_peak(depth);
+        self.calls.pop();
+    }
+
+    pub(super) fn token(&mut self, depth: usize) {
+        if let Some(stats) = self.current() {
+            stats.tokens += 1;
+        }
+        self.update_peak(depth);
+    }
+
+    fn update_peak(&mut self, depth: usize) {
+        if let Some(stats) = self.current() {
+            stats.peak_stack = stats.peak_stack.max(depth);
+        }
+    }
+
+    fn current(&mut self) -> Option<&mut FunctionStats> {
+        self.functions.get_mut(self.calls.last()?)
+    }
+
+    // one row per called function sorted by name, after a `function,calls,tokens,peak_stack` header
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("function,calls,tokens,peak_stack\n");
+        for (function, stats) in &self.functions {
+            csv.push_str(&format!(
+                "{},{},{},{}\n",
+                escape_csv(function),
+                stats.calls,
+                stats.tokens,
+                stats.peak_stack
+            ));
+        }
+        csv
+    }
+}
+
+// quotes the field when it contains a separator, a quote or a line break
+fn escape_csv(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[test]
+fn test_escape_csv() {
+    assert_eq!(escape_csv("main"), "main");
+    assert_eq!(escape_csv("a,b"), "\"a,b\"");
+    assert_eq!(escape_csv("say\"hi\""), "\"say\"\"hi\"\"\"");
+}
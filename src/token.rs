@@ -1,60 +1,241 @@
-#[derive(Debug)]
+use derive_more::Display;
+
+// every token is displayed as the source it is parsed from
+
+#[derive(Debug, Display)]
 pub enum MathOperator {
+    #[display(fmt = "+")]
     Add,
+    #[display(fmt = "-")]
     Sub,
+    #[display(fmt = "*")]
     Mul,
+    #[display(fmt = "/")]
+    Div, // unsigned, rounding towards zero
+    #[display(fmt = "%")]
+    Mod,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Display)]
+pub enum BitOperator {
+    #[display(fmt = "&")]
+    And,
+    #[display(fmt = "|")]
+    Or,
+    #[display(fmt = "^")]
+    Xor,
+    #[display(fmt = "<<")]
+    Shl,
+    #[display(fmt = ">>")]
+    Shr,
+    #[display(fmt = "~")]
+    Not, // the only unary operator, inverts every bit of the top of the stack
+}
+
+#[derive(Debug, Display)]
+pub enum LogicOperator {
+    #[display(fmt = "and")]
+    And,
+    #[display(fmt = "or")]
+    Or,
+    #[display(fmt = "not")]
+    Not,
+}
+
+#[derive(Debug, Display)]
 pub enum CmpOperator {
+    #[display(fmt = "<")]
     Less,
+    #[display(fmt = ">")]
     Greater,
+    #[display(fmt = "=")]
     Equal,
 }
 
-#[derive(Debug)]
+// operations on ascii characters
+#[derive(Debug, Display)]
+pub enum CharOperation {
+    #[display(fmt = "isdigit")]
+    IsDigit,
+    #[display(fmt = "isalpha")]
+    IsAlpha,
+    #[display(fmt = "isspace")]
+    IsSpace,
+    #[display(fmt = "toupper")]
+    ToUpper, // anything but ascii letters is left unchanged
+    #[display(fmt = "tolower")]
+    ToLower,
+}
+
+#[derive(Debug, Display)]
 pub enum StackOperation {
+    #[display(fmt = "dup")]
     Dup,
+    #[display(fmt = "swap")]
     Swap,
+    #[display(fmt = "over")]
     Over,
+    #[display(fmt = "rot")]
     Rot,
+    #[display(fmt = "drop")]
     Drop,
+    #[display(fmt = "nip")]
+    Nip, // removes the value below the top
+    #[display(fmt = "tuck")]
+    Tuck, // copies the top below the value under it
+    #[display(fmt = "2dup")]
+    TwoDup,
+    #[display(fmt = "2drop")]
+    TwoDrop,
+    #[display(fmt = "pick")]
+    Pick, // takes the index and copies the value that many positions below the top, so `0 pick` is `dup`
+    #[display(fmt = "pushframe")]
+    PushFrame, // takes the count and hides everything but that many values until the matching popframe
+    #[display(fmt = "popframe")]
+    PopFrame,
+    #[display(fmt = "depth")]
+    Depth, // pushes the number of values visible in the innermost frame
 }
 
 // <- to load variable
 // -> to store variable
-#[derive(Debug)]
+#[derive(Debug, Display)]
 pub enum MemoryOperation {
     // PushByte,           // pushes single byte into the local memory, returning the address,
+    #[display(fmt = "{:?}", "String::from_utf8_lossy(&_0[.._0.len() - 1])")]
     PushBytes(Vec<u8>), // pushes a sequence of bytes into local memory, returning the address,
-    StoreByte,          // takes the address from the stack and modifies the location in memory,
+    #[display(fmt = "->")]
+    StoreByte, // takes the address from the stack and modifies the location in memory,
+    #[display(fmt = "<-")]
     LoadByte,
+    #[display(fmt = "w->")]
+    StoreWord, // like the byte operations, but operating on a whole little-endian word
+    #[display(fmt = "w<-")]
+    LoadWord,
+    #[display(fmt = "free")]
     Free, // takes the address and count from the stack and clears local memory
+    #[display(fmt = "alloc")]
     Alloc,
+    #[display(fmt = "here")]
+    Here, // pushes the address the next allocation would start at
+    #[display(fmt = "ralloc")]
+    CountedAlloc, // takes the length, pushing the handle of a region that is freed once its reference count drops to zero
+    #[display(fmt = "raddr")]
+    CountedAddress, // takes the handle, pushing the address of its region
+    #[display(fmt = "incref")]
+    IncrementReferences,
+    #[display(fmt = "decref")]
+    DecrementReferences,
+    #[display(fmt = "sinit")]
+    ByteStackInit, // takes the base address and the capacity of a region used as a stack of bytes, which starts empty
+    #[display(fmt = "spush")]
+    ByteStackPush, // takes a byte and stores it on top of the byte stack
+    #[display(fmt = "spop")]
+    ByteStackPop,
+    #[display(fmt = "map_new")]
+    MapNew, // pushes the address of a new empty hash map from words to words
+    #[display(fmt = "map_set")]
+    MapSet, // takes the map, the value and the key on top, replacing the value when the key is already there
+    #[display(fmt = "map_get")]
+    MapGet, // takes the map and the key, pushing the value and whether it was found, the value is 0 when it wasn't
+    #[display(fmt = "memcpy")]
+    Copy, // takes the destination, source and length, copying the bytes even when the regions overlap
+    #[display(fmt = "memset")]
+    Fill, // takes the address, value and length, setting every byte in the region to the value
+    #[display(fmt = "incr")]
+    Increment, // takes the address and adds one to the word stored there, pushing the new value
+    #[display(fmt = "decr")]
+    Decrement,
+    #[display(fmt = "crlf2lf")]
+    CrlfToLf, // takes the address and length, replacing every \r\n with \n in place and pushing the new length
+    #[display(fmt = "editdist")]
+    EditDistance, // takes two address and length pairs, pushing the levenshtein distance between them
+    #[display(fmt = "itoa_base")]
+    FormatBase, // takes the value and the base from 2 to 36, writing the digits into new memory and pushing its address and length
+    #[display(fmt = "crc32")]
+    Crc32, // takes the address and length, pushing the crc-32 checksum of the bytes, like the one of zip and png
+    #[display(fmt = "histogram")]
+    Histogram, // takes the source address and length and the destination address, storing the count of each byte value as 256 words
 }
 
+// dense case labels are dispatched by indexing instead of comparing the value with every label
 #[derive(Debug)]
+pub struct JumpTable {
+    pub start: usize,             // the smallest label
+    pub arms: Vec<Option<usize>>, // index of the arm for every value from start, None for the gaps between labels
+}
+
+// blocks are displayed as the keyword opening them
+#[derive(Debug, Display)]
 pub enum Token {
     Push(usize),           // push value onto stack
     Math(MathOperator), // operations taking two values from the stack and pushing result of math operation onto stack
     Cmp(CmpOperator),   // operations taking two values from the stack and pushing either 0 or 1
+    Bit(BitOperator),   // operations on the individual bits of the values
     Stack(StackOperation), // operation operating directly on stack
+    Logic(LogicOperator), // operations treating any nonzero value as true and pushing either 0 or 1
+    Char(CharOperation), // operations classifying and converting ascii characters
     Memory(MemoryOperation),
-    FunctionCall(String),
+    FunctionCall(usize), // index of the called function in the program
 
     // TODO: review control flow for the language
+    #[display(fmt = "if")]
     IfBlock(Vec<Token>, Vec<Token>), // if statement, consuming boolean value from stack
+    #[display(fmt = "?")]
+    Conditional(Vec<Token>, Vec<Token>), // like if, but both blocks are checked to leave a single value
     // TODO: deprecate loop in favour of while 1
-    LoopBlock(Vec<Token>), // infinite loop. To exit loop use break
+    #[display(fmt = "loop")]
+    LoopBlock(Vec<Token>, Option<String>), // infinite loop. To exit loop use break, the label lets nested loops break it
 
-    WhileBlock(Vec<Token>, Vec<Token>), // first is the condition, the second is the body of the loop
-    Continue,
-    Break,                             // exit the loop
+    #[display(fmt = "while")]
+    WhileBlock(Vec<Token>, Vec<Token>, Option<String>), // first is the condition, the second is the body of the loop, then the label
+    #[display(fmt = "for")]
+    ForBlock(String, Vec<Token>, Vec<Token>, Vec<Token>), // the counter binding, the start and end of its half-open range, and the body
+    #[display(fmt = "for")]
+    RepeatBlock(Vec<Token>), // runs the body as many times as the count taken from the stack
+    #[display(fmt = "match")]
+    MatchBlock(Vec<(usize, Vec<Token>)>, Vec<Token>, Option<JumpTable>), // the arms with their labels, the else block and the jump table when the labels are dense
+    #[display(fmt = "continue")]
+    Continue(Option<String>), // the label of the loop to continue, the innermost one when there is none
+    #[display(fmt = "break")]
+    Break(Option<String>), // exit the loop
+    #[display(fmt = "return")]
+    Return, // exit the function, from any loop or block inside it
+    #[display(fmt = "yield")]
+    Yield, // pauses the run, handing control back to the host until it resumes the machine
+    #[display(fmt = "let")]
     LetBlock(Vec<Token>, Vec<String>), // scope for the let bindings,
-    Let(String),                       // get let binding
+    Let(String), // get let binding
+    #[display(fmt = "=> {}", _0)]
+    SetLet(String), // takes the new value of the innermost binding with the name, until its scope ends
 
     // TODO: this methods must be replaced by sane as soon as some type system is developed. This methods are absurd and only exist for the purpose of developing the basic language syntax
+    #[display(fmt = "putc")]
     Putc, // prints the top of the stack
+    #[display(fmt = "putu")]
     Putu,
+    #[display(fmt = "puti")]
+    Puti, // prints the top of the stack as a signed value
+    #[display(fmt = "puts")]
+    Puts, // takes the address of a zero terminated string and prints the bytes before the terminator
+    #[display(fmt = "redirect")]
+    Redirect, // captures the output until the matching endredirect
+    #[display(fmt = "endredirect")]
+    EndRedirect, // pushes the address and length of the captured output, copied into memory
+    #[display(fmt = "getc")]
+    Getc, // reads a single byte of input, pushing usize::MAX at the end of input
+    #[display(fmt = "getu")]
+    Getu, // reads a whitespace delimited unsigned integer
+    #[display(fmt = "readline")]
+    Readline, // reads a line including the newline into memory, pushing its address and length
+    #[display(fmt = "getenv")]
+    Getenv, // takes the address of the zero terminated name, pushing the address and length of the value and whether it was found
+    #[display(fmt = "rand")]
+    Rand, // pushes the next pseudo random word
+    #[display(fmt = "seed")]
+    Seed, // takes the new state of the random generator, making the following `rand` values repeatable
+    #[display(fmt = "getseed")]
+    GetSeed, // pushes the current state of the random generator
+    #[display(fmt = "???")]
     Debug, // prints the whole stack
 }